@@ -0,0 +1,67 @@
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+
+use crate::layer::{Layer, LayerKind};
+
+// Secret keys read by a deserialize or getter at any point during the run
+static ACCESSED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+// Keys set by yaml secrets files. Secrets from .env files are env vars by the time they are
+// loaded, so they can't be told apart from other env vars
+pub(crate) fn secret_keys(layers: &[Layer]) -> Result<BTreeSet<String>> {
+    let mut keys = BTreeSet::new();
+    for layer in layers
+        .iter()
+        .filter(|layer| layer.kind == LayerKind::Secret)
+    {
+        keys.extend(layer.values()?.into_keys());
+    }
+    Ok(keys)
+}
+
+// A secret key counts as read unless serde ignored it (or a section it is in) because `T` has no
+// field for it
+pub(crate) fn deserialize<T: DeserializeOwned>(
+    config: config::Config,
+    secret_keys: &BTreeSet<String>,
+) -> Result<T> {
+    let mut ignored = vec![];
+    let mut callback = |path: serde_ignored::Path<'_>| ignored.push(path.to_string());
+    let deserializer = serde_ignored::Deserializer::new(config, &mut callback);
+    let value = crate::deserialize_at(deserializer, None)?;
+    record(
+        secret_keys
+            .iter()
+            .filter(|key| !ignored.iter().any(|path| is_within(key, path))),
+    );
+    Ok(value)
+}
+
+pub(crate) fn record_get(key: &str, secret_keys: &BTreeSet<String>) {
+    record(secret_keys.iter().filter(|secret| is_within(secret, key)));
+}
+
+pub(crate) fn unused(secret_keys: &BTreeSet<String>) -> Vec<String> {
+    let accessed = ACCESSED.get_or_init(Default::default).lock().unwrap();
+    secret_keys
+        .iter()
+        .filter(|key| !accessed.contains(*key))
+        .cloned()
+        .collect()
+}
+
+fn record<'a>(keys: impl Iterator<Item = &'a String>) {
+    let mut accessed = ACCESSED.get_or_init(Default::default).lock().unwrap();
+    accessed.extend(keys.cloned());
+}
+
+// Whether `key` is `path` itself or somewhere under it
+fn is_within(key: &str, path: &str) -> bool {
+    key == path
+        || key
+            .strip_prefix(path)
+            .is_some_and(|rest| rest.starts_with(['.', '[']))
+}
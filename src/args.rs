@@ -0,0 +1,104 @@
+use anyhow::{bail, Result};
+use config::{Value, ValueKind};
+
+// Parses `key=value` overrides, eg. from `--set server.port=8080`. Values are coerced to bools and
+// numbers where they parse as one, and a key given more than once becomes a list of its values
+pub(crate) fn parse(args: &[String]) -> Result<Vec<(String, Value)>> {
+    let mut parsed: Vec<(String, Vec<Value>)> = vec![];
+    for arg in args {
+        let Some((key, value)) = arg.split_once('=') else {
+            bail!("Invalid override {arg}, expected key=value");
+        };
+        let key = key.trim().to_lowercase();
+        if key.is_empty() {
+            bail!("Invalid override {arg}, the key is empty");
+        }
+        let value = Value::new(None, coerce(value));
+        match parsed.iter_mut().find(|(parsed_key, _)| *parsed_key == key) {
+            Some((_, values)) => values.push(value),
+            None => parsed.push((key, vec![value])),
+        }
+    }
+
+    Ok(parsed
+        .into_iter()
+        .map(|(key, mut values)| {
+            let value = if values.len() == 1 {
+                values.pop().unwrap()
+            } else {
+                Value::new(None, ValueKind::Array(values))
+            };
+            (key, value)
+        })
+        .collect())
+}
+
+// Only values that read back exactly as given are converted, so eg. `1.10` and `007` stay strings
+pub(crate) fn coerce(value: &str) -> ValueKind {
+    let kind = if let Ok(value) = value.parse() {
+        ValueKind::Boolean(value)
+    } else if let Ok(value) = value.parse() {
+        ValueKind::I64(value)
+    } else if let Ok(value) = value.parse() {
+        ValueKind::Float(value)
+    } else {
+        return ValueKind::String(value.to_owned());
+    };
+    if kind.to_string() != value {
+        return ValueKind::String(value.to_owned());
+    }
+    kind
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coerces_values_that_read_back_the_same() {
+        assert_eq!(coerce("true"), ValueKind::Boolean(true));
+        assert_eq!(coerce("8080"), ValueKind::I64(8080));
+        assert_eq!(coerce("-3"), ValueKind::I64(-3));
+        assert_eq!(coerce("1.5"), ValueKind::Float(1.5));
+        assert_eq!(coerce("localhost"), ValueKind::String("localhost".into()));
+    }
+
+    #[test]
+    fn keeps_values_that_would_change_as_strings() {
+        assert_eq!(coerce("1.10"), ValueKind::String("1.10".into()));
+        assert_eq!(coerce("007"), ValueKind::String("007".into()));
+        assert_eq!(coerce("+5"), ValueKind::String("+5".into()));
+        assert_eq!(coerce("1e3"), ValueKind::String("1e3".into()));
+        assert_eq!(coerce(""), ValueKind::String(String::new()));
+    }
+
+    #[test]
+    fn parses_overrides() {
+        let args = ["server.port=8080", "Tags=a", "tags=b", "version=1.10"].map(String::from);
+        let parsed = parse(&args).unwrap();
+        let kinds: Vec<(&str, &ValueKind)> = parsed
+            .iter()
+            .map(|(key, value)| (key.as_str(), &value.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("server.port", &ValueKind::I64(8080)),
+                (
+                    "tags",
+                    &ValueKind::Array(vec![
+                        Value::new(None, "a"),
+                        Value::new(None, "b")
+                    ])
+                ),
+                ("version", &ValueKind::String("1.10".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_overrides() {
+        assert!(parse(&["server.port".into()]).is_err());
+        assert!(parse(&["=1".into()]).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use rusqlite::{Connection, OpenFlags};
+
+use crate::layer::{Layer, LayerKind};
+use crate::{args, tree};
+
+// A SQLite table of (key, value) rows, eg. for dynamic config that operators edit through an
+// admin tool. Keys are dotted paths, and values are parsed as bools and numbers where possible
+#[derive(Debug, Clone)]
+pub struct DbSource {
+    pub path: PathBuf,
+    pub table: String,
+    pub precedence: DbPrecedence,
+}
+
+// Where the table sits in the precedence chain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DbPrecedence {
+    // Below every file, so it only provides defaults
+    BelowFiles,
+    // Above every file and below env vars
+    #[default]
+    AboveFiles,
+    // Above env vars too (but below local-overrides.yaml)
+    AboveEnv,
+}
+
+pub(crate) fn layer(source: &DbSource) -> Result<Layer> {
+    // The table name can't be a query parameter
+    if source.table.is_empty()
+        || !source
+            .table
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        bail!("Invalid config table name {}", source.table);
+    }
+
+    let connection = Connection::open_with_flags(&source.path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open {}", source.path.display()))?;
+    let mut statement = connection
+        .prepare(&format!("SELECT key, value FROM {}", source.table))
+        .with_context(|| format!("Failed to read config table {}", source.table))?;
+    let rows = statement.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+
+    let mut table = config::Map::new();
+    for row in rows {
+        let (key, value) = row?;
+        let value = config::Value::new(None, args::coerce(&value));
+        tree::set_path(&mut table, &key, value);
+    }
+    let name = format!("{}#{}", source.path.display(), source.table);
+    Ok(Layer::new(name, LayerKind::File, tree::TreeSource(table)))
+}
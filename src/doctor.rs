@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::layer::LayerKind;
+use crate::prelude;
+use crate::{config_file_names, current, Environment, InitOptions, DEFAULT_ENV, INIT};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+        }
+    }
+}
+
+// Checks for likely misconfigurations, eg. for a `--doctor` flag whose output can be attached to
+// bug reports. Works whether or not the config is initialized, though checks on what was loaded
+// (secrets, shadowed values) only run once it is
+pub fn doctor() -> Vec<Diagnostic> {
+    let default_options = InitOptions::default();
+    let options = INIT.get().map_or(&default_options, |init| &init.options);
+    let files = options.files();
+    let loaded = current().ok();
+    let mut diagnostics = vec![];
+
+    if let Err(e) = prelude::read(files) {
+        diagnostics.push(Diagnostic::new(Severity::Error, format!("{e:#}")));
+    }
+    let loader_var = |var: &str| prelude::var(var, files).ok().flatten();
+    let config_dir = match loader_var("CONFIG_DIR") {
+        Some(config_dir) => config_dir,
+        None => {
+            diagnostics.push(Diagnostic::new(
+                Severity::Info,
+                "CONFIG_DIR is not set, defaulting to ./conf",
+            ));
+            "./conf".into()
+        }
+    };
+    match files.is_dir(Path::new(&config_dir)) {
+        Ok(true) => {}
+        Ok(false) => diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            format!("CONFIG_DIR '{config_dir}' is not a directory"),
+        )),
+        Err(e) => diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            format!("CONFIG_DIR '{config_dir}' can't be read: {e}"),
+        )),
+    }
+
+    let env_var = loader_var("ENV");
+    let env = match (&loaded, &env_var) {
+        (Some(loaded), _) => loaded.env,
+        (None, Some(env)) => match Environment::from_str(env) {
+            Ok(env) => env,
+            Err(_) => {
+                diagnostics.push(Diagnostic::new(
+                    Severity::Error,
+                    format!("ENV is set to '{env}', which is not a known environment"),
+                ));
+                return diagnostics;
+            }
+        },
+        (None, None) => Environment::from_str(DEFAULT_ENV).unwrap_or(Environment::Dev),
+    };
+    if env_var.is_none() && options.environment.is_none() {
+        diagnostics.push(Diagnostic::new(
+            Severity::Warning,
+            format!("ENV is not set, defaulting to the {DEFAULT_ENV} environment"),
+        ));
+    }
+
+    let present: Vec<String> = config_file_names(&env, options.env_file_suffix())
+        .into_iter()
+        .filter(|name| files.read(&Path::new(&config_dir).join(name)).is_ok())
+        .collect();
+    if present.is_empty() {
+        diagnostics.push(Diagnostic::new(
+            Severity::Warning,
+            format!("No config files for the {env} environment found in '{config_dir}'"),
+        ));
+    }
+    if env.is_production() && present.contains(&format!("{env}-secrets.yaml")) {
+        diagnostics.push(Diagnostic::new(
+            Severity::Warning,
+            format!("Plaintext secrets file {env}-secrets.yaml is present in production"),
+        ));
+    }
+
+    let Some(loaded) = loaded else {
+        diagnostics.push(Diagnostic::new(
+            Severity::Info,
+            "Config is not initialized, skipping the checks on loaded values",
+        ));
+        return diagnostics;
+    };
+
+    let secrets = &loaded.info.secrets;
+    if !secrets.key_present && !secrets.files.is_empty() {
+        diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            "Encrypted secrets files are present, but SECRETS_ENCRYPTION_KEY is not set",
+        ));
+    } else {
+        for (path, _) in secrets.files.iter().filter(|(_, ok)| !ok) {
+            diagnostics.push(Diagnostic::new(
+                Severity::Error,
+                format!("{} could not be decrypted", path.display()),
+            ));
+        }
+    }
+
+    let mut secret_values = BTreeMap::new();
+    let mut env_values = BTreeMap::new();
+    for layer in &loaded.info.layers {
+        let values = match layer.kind {
+            LayerKind::Secret => &mut secret_values,
+            LayerKind::Env => &mut env_values,
+            LayerKind::File => continue,
+        };
+        if let Ok(layer_values) = layer.values() {
+            values.extend(layer_values);
+        }
+    }
+    for key in env_values
+        .keys()
+        .filter(|key| secret_values.contains_key(*key))
+    {
+        diagnostics.push(Diagnostic::new(
+            Severity::Warning,
+            format!("An env var overrides the value of {key} from secrets files"),
+        ));
+    }
+
+    diagnostics
+}
@@ -0,0 +1,102 @@
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use config::{Value, ValueKind};
+
+use crate::files::{self, FileProvider};
+use crate::secrets;
+use crate::transform::ValueTransform;
+use crate::yaml::BlockScalars;
+
+const FILE_TAG: &str = "!file ";
+
+// yaml-rust2 drops the tags of scalars it doesn't know, so `!file` values are rewritten to a
+// string holding this marker before parsing, and resolved once everything has been merged. A
+// `!file` value overridden by eg. an env var is then never read
+const MARKER: &str = "\u{1}simple_config_loader_file:";
+
+// Replaces every `!file <path>` value in a yaml file with a marked string holding the path
+pub(crate) fn mark(text: &str) -> String {
+    if !text.contains(FILE_TAG) {
+        return text.to_owned();
+    }
+
+    let mut marked = String::with_capacity(text.len());
+    let mut block_scalars = BlockScalars::default();
+    for line in text.lines() {
+        let in_block_scalar = block_scalars.contains(line);
+        let tagged = line.find(FILE_TAG).filter(|&pos| {
+            let before = line[..pos].trim_end();
+            !in_block_scalar && (before.ends_with(':') || before.ends_with('-'))
+        });
+        let Some(pos) = tagged else {
+            marked.push_str(line);
+            marked.push('\n');
+            continue;
+        };
+
+        let rest = &line[pos + FILE_TAG.len()..];
+        let (path, comment) = match rest.find(" #") {
+            Some(comment) => (&rest[..comment], &rest[comment..]),
+            None => (rest, ""),
+        };
+        marked.push_str(&line[..pos]);
+        marked.push_str(&secrets::quote(&format!("{MARKER}{}", path.trim())));
+        marked.push_str(comment);
+        marked.push('\n');
+    }
+    marked
+}
+
+// Substitutes the contents of the referenced file for every `!file` value left after merging,
+// with relative paths resolved against the config directory
+#[derive(Debug)]
+pub(crate) struct FileRefs<'a> {
+    pub(crate) config_dir: &'a Path,
+    pub(crate) files: &'a dyn FileProvider,
+}
+
+impl ValueTransform for FileRefs<'_> {
+    fn transform(&self, key: &str, value: &mut Value) -> Result<()> {
+        let ValueKind::String(s) = &mut value.kind else {
+            return Ok(());
+        };
+        let Some(path) = s.strip_prefix(MARKER) else {
+            return Ok(());
+        };
+
+        let path = self.config_dir.join(path);
+        let contents = match self.files.read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                bail!(
+                    "{key} refers to {} with !file, which does not exist",
+                    path.display()
+                )
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read {} for {key}", path.display()))
+            }
+        };
+        *s = files::text(&path.to_string_lossy(), contents)
+            .with_context(|| format!("Failed to read {} for {key}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_file_refs() {
+        let text = "cert: !file certs/app.pem # rotated yearly\nscript: |\n  cp: !file not/a/ref\n";
+        let marked = secrets::quote(&format!("{MARKER}certs/app.pem"));
+        assert_eq!(
+            mark(text),
+            format!("cert: {marked} # rotated yearly\nscript: |\n  cp: !file not/a/ref\n")
+        );
+    }
+}
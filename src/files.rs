@@ -0,0 +1,160 @@
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+
+// Where the loader reads config files from. Defaults to `std::fs`, and can be swapped out at init
+// for an in-memory or remote filesystem while keeping all of the layering logic. Only reads go
+// through it, set_persisted() always writes to the real filesystem
+pub trait FileProvider: Debug + Send + Sync {
+    // Fails with `io::ErrorKind::NotFound` for files that don't exist, which the loader skips
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    // Resolves a config dir to the form reported by config_dir(). Defaults to the path unchanged
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(path.to_path_buf())
+    }
+
+    // Whether the path is a directory, failing with `io::ErrorKind::NotFound` if it doesn't exist.
+    // Defaults to true, for filesystems without real directories
+    fn is_dir(&self, _path: &Path) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    // The modification time and size of a file, which lets reload() skip reading and decrypting
+    // encrypted files that haven't changed. Defaults to `None`, for filesystems without mtimes,
+    // whose files are read again and compared by digest
+    fn modified(&self, _path: &Path) -> io::Result<Option<(SystemTime, u64)>> {
+        Ok(None)
+    }
+
+    // Where the file can be read on the local filesystem, so encrypted files are decrypted in
+    // place. Defaults to `None`, and encrypted files are decrypted from their contents through a
+    // private temp file instead
+    fn local_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdFs;
+
+impl FileProvider for StdFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> io::Result<bool> {
+        Ok(fs::metadata(path)?.is_dir())
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<Option<(SystemTime, u64)>> {
+        let metadata = fs::metadata(path)?;
+        Ok(Some((metadata.modified()?, metadata.len())))
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(path.to_path_buf())
+    }
+}
+
+// The contents of a config file as text, without the UTF-8 byte order mark some editors put at the
+// start, which would otherwise end up in the first key. Fails for contents that aren't UTF-8
+pub(crate) fn text(path: &str, contents: Vec<u8>) -> Result<String> {
+    let mut text =
+        String::from_utf8(contents).with_context(|| format!("{path} is not valid UTF-8"))?;
+    if text.starts_with('\u{feff}') {
+        text.remove(0);
+    }
+    Ok(text)
+}
+
+// Hides every file not named in `names`, for reproducible loads
+#[derive(Debug)]
+pub(crate) struct AllowedFiles<'a> {
+    pub(crate) files: &'a dyn FileProvider,
+    pub(crate) names: &'a [String],
+}
+
+impl AllowedFiles<'_> {
+    fn allowed(&self, path: &Path) -> io::Result<()> {
+        let allowed = path
+            .file_name()
+            .is_some_and(|name| self.names.iter().any(|allowed| name == allowed.as_str()));
+        if !allowed {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(())
+    }
+}
+
+impl FileProvider for AllowedFiles<'_> {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.allowed(path)?;
+        self.files.read(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.files.canonicalize(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> io::Result<bool> {
+        self.files.is_dir(path)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<Option<(SystemTime, u64)>> {
+        self.allowed(path)?;
+        self.files.modified(path)
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        self.allowed(path).ok()?;
+        self.files.local_path(path)
+    }
+}
+
+// Files held in memory by path, for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct MemFs(std::collections::HashMap<PathBuf, Vec<u8>>);
+
+#[cfg(test)]
+impl MemFs {
+    pub(crate) fn new(files: &[(&str, &str)]) -> Self {
+        Self(
+            files
+                .iter()
+                .map(|(path, text)| (path.into(), text.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+impl FileProvider for MemFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.0
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_the_byte_order_mark() {
+        let stripped = text("default.yaml", b"\xef\xbb\xbfa: 1\n".to_vec()).unwrap();
+        assert_eq!(stripped, "a: 1\n");
+        assert!(text("default.yaml", vec![0xff]).is_err());
+    }
+}
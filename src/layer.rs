@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+
+use config::{ConfigError, Map, Source, Value, ValueKind};
+
+use crate::tree;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LayerKind {
+    File,
+    // Secrets files, whether plaintext or decrypted
+    Secret,
+    Env,
+}
+
+// One source in the precedence chain. Every value it yields has its origin set to the layer's
+// name, so the merged config records where each value came from
+#[derive(Debug, Clone)]
+pub(crate) struct Layer {
+    pub(crate) name: String,
+    pub(crate) kind: LayerKind,
+    source: Box<dyn Source + Send + Sync>,
+}
+
+impl Layer {
+    pub(crate) fn new(
+        name: impl Into<String>,
+        kind: LayerKind,
+        source: impl Source + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            source: Box::new(source),
+        }
+    }
+
+    // Env vars read with `prefix`, named eg. `env (APP)` so that their values record the prefix
+    pub(crate) fn env(prefix: Option<&str>, source: impl Source + Send + Sync + 'static) -> Self {
+        let name = match prefix {
+            Some(prefix) => format!("env ({prefix})"),
+            None => "env".into(),
+        };
+        Self::new(name, LayerKind::Env, source)
+    }
+
+    // The prefix an env layer was read with, `Some(None)` for env vars without a prefix
+    pub(crate) fn env_prefix(&self) -> Option<Option<&str>> {
+        if self.kind != LayerKind::Env {
+            return None;
+        }
+        Some(
+            self.name
+                .strip_prefix("env (")
+                .and_then(|name| name.strip_suffix(')')),
+        )
+    }
+
+    // Values of this layer alone, by dotted key
+    pub(crate) fn values(&self) -> anyhow::Result<BTreeMap<String, Value>> {
+        Ok(tree::flatten(&self.collect()?))
+    }
+}
+
+impl Source for Layer {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        Ok(self
+            .source
+            .collect()?
+            .into_iter()
+            .map(|(key, value)| (key, with_origin(value, &self.name)))
+            .collect())
+    }
+}
+
+fn with_origin(value: Value, origin: &String) -> Value {
+    let kind = match value.kind {
+        ValueKind::Table(table) => ValueKind::Table(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, with_origin(value, origin)))
+                .collect(),
+        ),
+        ValueKind::Array(array) => ValueKind::Array(
+            array
+                .into_iter()
+                .map(|value| with_origin(value, origin))
+                .collect(),
+        ),
+        kind => kind,
+    };
+    Value::new(Some(origin), kind)
+}
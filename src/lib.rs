@@ -1,144 +1,3206 @@
+#[cfg(feature = "secret-telemetry")]
+mod access;
+mod args;
+#[cfg(feature = "sqlite")]
+mod db;
+mod doctor;
+mod file_refs;
+mod files;
+mod layer;
+mod manifest;
+mod persist;
+mod prelude;
+mod render;
+#[cfg(feature = "schema")]
+mod schema;
+mod secrets;
+mod transform;
+mod tree;
+mod validation;
+mod vars;
+#[cfg(feature = "vault")]
+mod vault;
+mod yaml;
+
+use std::any::{self, Any, TypeId};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::OnceLock;
+use std::sync::{mpsc, Arc, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::Duration;
 
-use anyhow::Result;
-use config::{Case, FileFormat};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use config::{Case, FileFormat, Source};
 use serde::de::DeserializeOwned;
-use simple_encrypt::decrypt_file;
+use sha2::Digest;
+use strum::IntoEnumIterator;
+
+use layer::{Layer, LayerKind};
+use manifest::{Manifest, VerifiedFiles};
+use vars::Vars;
+
+// Prints a diagnostic message unless InitOptions::quiet is set
+macro_rules! diag {
+    ($options:expr, $($arg:tt)*) => {
+        if !$options.quiet {
+            println!($($arg)*);
+        }
+    };
+}
 
-#[derive(strum::EnumString, strum::Display)]
+#[cfg(feature = "sqlite")]
+pub use db::{DbPrecedence, DbSource};
+pub use doctor::{doctor, Diagnostic, Severity};
+pub use files::{FileProvider, StdFs};
+#[cfg(feature = "schema")]
+pub use schema::{env_template, list_parse_keys_of, schema_of};
+// Deserializes from any config value, eg. `password: SecretString` in a LoadConfig struct, and
+// is redacted in Debug output. The value is read with `expose_secret()`
+#[cfg(feature = "secrecy")]
+pub use secrecy::{ExposeSecret, SecretString};
+pub use transform::{KeyTransformFn, TransformFn, ValueTransform};
+pub use validation::ValidationError;
+pub use yaml::YamlLimits;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, strum::EnumString, strum::Display, strum::EnumIter,
+)]
 #[strum(serialize_all = "lowercase")]
-enum Environment {
+pub enum Environment {
     Dev,
     Stag,
     Prod,
 }
 
-static CONFIG: OnceLock<config::Config> = OnceLock::new();
+impl Environment {
+    // Whether this is a production environment, which any prod-like environments added later
+    // count as too
+    pub fn is_production(&self) -> bool {
+        matches!(self, Environment::Prod)
+    }
+}
+
+// Primitive types that env var values can be checked against before deserialization
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum ValueType {
+    Bool,
+    Int,
+    Float,
+    String,
+}
+
+// A yaml file in the config dir that is only loaded when its predicate holds, eg. `gpu.yaml` only
+// when GPU_ENABLED is set. The predicate is checked on every load, including reloads
+#[derive(Clone)]
+pub struct ConditionalSource {
+    predicate: Arc<dyn Fn() -> bool + Send + Sync>,
+    file_name: String,
+}
+
+impl ConditionalSource {
+    pub fn new(
+        predicate: impl Fn() -> bool + Send + Sync + 'static,
+        file_name: impl Into<String>,
+    ) -> Self {
+        Self {
+            predicate: Arc::new(predicate),
+            file_name: file_name.into(),
+        }
+    }
+}
+
+impl std::fmt::Debug for ConditionalSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConditionalSource")
+            .field("file_name", &self.file_name)
+            .finish_non_exhaustive()
+    }
+}
+
+// Where the env source sits in the precedence chain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EnvPrecedence {
+    // Above all files, so any env var overrides them (the usual behaviour)
+    #[default]
+    Highest,
+    // Below all files, so checked in files are authoritative and env vars only fill gaps, for
+    // environments such as CI that inject many env vars of their own
+    Lowest,
+}
+
+// What happens when a secrets file sets a key that a plaintext yaml file also sets, which is
+// usually fine for obviously secret keys but surprising for any other
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SecretOverrides {
+    // Print a warning listing the keys (the default)
+    #[default]
+    Warn,
+    // Fail to load
+    Error,
+}
+
+// Settings for a load whose result only depends on the files listed and on env vars that are set
+// deliberately, eg. to compare the merged config against a checked in snapshot
+#[derive(Debug, Clone)]
+pub struct Reproducible {
+    // The environment to load, whatever ENV is set to
+    pub env: Environment,
+    // Whether env vars with the declared prefix (or a fallback prefix) are read. No other env
+    // vars are ever read (which includes SECRETS_ENCRYPTION_KEY, unless it's set in one of the
+    // listed .env files), and without a prefix no env vars are read at all
+    pub prefixed_env_vars: bool,
+    // Names of the files that may be loaded from the config dirs, eg. `default.yaml`. All other
+    // files are ignored
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InitOptions {
+    // Prefix for env vars, eg. `AST` so that `AST__DEBUG=1` sets the `debug` key. Env var names
+    // are matched ignoring case: the prefix, the `__` separated key and the prefix as declared
+    // here can each be in any case, so `ast__server__port` and `AST__SERVER__PORT` both set
+    // `server.port`. Keys always end up lowercase
+    pub prefix: Option<String>,
+    // Further env var prefixes read below `prefix`, from highest to lowest precedence, eg. the old
+    // prefix while migrating to a new one. list_parse_keys, env_value_types and env_allowlist
+    // apply to them too, the other env var options only to `prefix`. origin() tells which prefix
+    // set a key
+    pub fallback_prefixes: Vec<String>,
+    // Env var prefixes for sub-trees of the config, as (env var prefix, config key) pairs, eg.
+    // `("DB", "database")` so that `DB__HOST` sets `database.host`. Their vars sit alongside the
+    // ones with `prefix`, and only env_allowlist of the other env var options applies to them
+    pub env_prefix_map: Vec<(String, String)>,
+    // Keys that must be parsed from env vars as Vec<String> rather than String. With the `schema`
+    // feature, list_parse_keys_of() finds them from the config struct
+    pub list_parse_keys: Vec<String>,
+    // Additional list-parse keys that only apply when running in the given environment,
+    // for keys that are a list in one environment but a single value in another
+    pub env_list_parse_keys: HashMap<Environment, Vec<String>>,
+    // Error out (rather than warn) when a .env file is present but fails to parse
+    pub strict_env_files: bool,
+    // Expected types of keys that may be set from env vars. All env vars that don't parse as the
+    // type of their key are reported together, instead of as one opaque deserialize error
+    pub env_value_types: HashMap<String, ValueType>,
+    // Suffix of the dotenv files, for deployments whose legacy tooling names them eg. `local.conf`
+    // rather than `local.env`. Defaults to `.env`
+    pub env_file_suffix: Option<String>,
+    // Error when ENV is unset instead of defaulting to DEFAULT_ENV, so that a production image that
+    // forgot to set ENV fails at startup rather than quietly running with dev config
+    pub forbid_default_env: bool,
+    // Renamed keys as (old key, new key) pairs, eg. `("db.host", "database.host")`. Values still
+    // set under an old key are moved to the new one with a deprecation warning
+    pub key_renames: Vec<(String, String)>,
+    // Shared config dir (eg. an org-wide config repo) whose full file set is loaded below the
+    // files in CONFIG_DIR, which then only need to override what differs for the service
+    pub base_dir: Option<PathBuf>,
+    // Also load the full file set from CONFIG_DIR/<env>/ (eg. `conf/prod/`), above the files in
+    // CONFIG_DIR itself, which then act as the defaults shared by every environment
+    pub env_dirs: bool,
+    // Fail to load when the environment has no files of its own (<env>.yaml, its secrets or .env
+    // files, or with env_dirs a CONFIG_DIR/<env>/ dir), which is almost always a deployment mistake
+    pub require_env_files: bool,
+    // Load only the files shared by every environment (default.yaml, default.env and their secrets
+    // files) and env vars, skipping the files of the environment, any CONFIG_DIR/<env>/ dir, the
+    // local files (.env, local.*, local-overrides.yaml) and profile and conditional files, eg. for
+    // linters and doc generators that work on the shared defaults. Overrides require_env_files
+    pub defaults_only: bool,
+    // Files in CONFIG_DIR that must be present, eg. `default.yaml`. Every other file is optional
+    pub required_files: Vec<String>,
+    // Keys whose values from secrets files can't be overridden by env vars. Env vars that shadow a
+    // value from a secrets file for any other key are only warned about
+    pub secrets_authoritative_keys: Vec<String>,
+    // Let yaml files reference anchors defined in lower precedence yaml files, eg. an anchor in
+    // default.yaml from prod.yaml
+    pub yaml_cross_file_anchors: bool,
+    // Limits on the size and structure of yaml files, none by default
+    pub yaml_limits: YamlLimits,
+    // Yaml files loaded only when their predicate holds. They sit above the env and secrets yaml
+    // files and below local.yaml, in the order given
+    pub conditional_sources: Vec<ConditionalSource>,
+    // Profiles to activate, eg. `["highmem", "gpu"]`, each loading a `profile-<name>.yaml` above
+    // the env and secrets yaml files and below the conditional sources, in the order given. Without
+    // it, profiles are read from the comma separated PROFILES env var
+    pub profiles: Option<Vec<String>>,
+    // Env var holding the whole config as base64 encoded yaml, eg. `APP_CONFIG_B64`, for platforms
+    // that only allow injecting a single env var. It sits above every file and below the other env
+    // vars
+    pub config_blob_var: Option<String>,
+    // A SQLite table merged into the config. If the database can't be read, the config is loaded
+    // without it and a warning is printed
+    #[cfg(feature = "sqlite")]
+    pub db_source: Option<DbSource>,
+    // Patterns of keys that may only be set by secrets files or env vars, never by a plaintext
+    // yaml file, eg. `*.password`. `*` matches any run of characters, dots included. Loading fails
+    // if the merged value of a matching key came from a plaintext yaml file
+    pub secret_only_keys: Vec<String>,
+    // What to do when a value from a secrets file (or other secrets source) overrides a value
+    // from a plaintext yaml file for the same key
+    pub secret_overrides: SecretOverrides,
+    // Fail to load if any string value in the merged config still equals the placeholder
+    // (`CHANGE_ME` unless set), eg. for values in default.yaml that every deployment must override
+    pub reject_placeholders: bool,
+    pub placeholder: Option<String>,
+    // Top-level sections the config may have, eg. `["server", "database", "logging"]`. Loading
+    // fails if any other top-level key is set, which is usually a typo or left over config. With
+    // an app_namespace, these are the sections under it
+    pub allowed_sections: Option<Vec<String>>,
+    // Whether env vars override the files (the default) or only fill in what they don't set.
+    // local-overrides.yaml stays on top either way
+    pub env_precedence: EnvPrecedence,
+    // Bound on the total time a load (at init and on every reload) may take, so that an
+    // unresponsive network-backed source fails startup instead of hanging it. The load runs on a
+    // worker thread, which is left behind if it times out
+    pub init_timeout: Option<Duration>,
+    // Trim leading and trailing whitespace from every string value
+    pub trim_strings: bool,
+    // Substitute `${VAR}` and `${VAR:-default}` in string values from the process env, eg.
+    // `https://${HOSTNAME}/api`. This runs before the transforms below. A variable that isn't set
+    // and has no default fails the load, unless keep_unresolved_env_refs leaves it as written
+    pub expand_env_refs: bool,
+    pub keep_unresolved_env_refs: bool,
+    // Expand a leading `~` in string values to the home dir, eg. `~/data`
+    pub expand_home: bool,
+    // Applied to every scalar value after the built-in transforms above, for rewrites of its own
+    pub value_transform: Option<TransformFn>,
+    // Further steps for the transform pipeline, run after all of the above in the order given
+    pub value_transforms: Vec<Arc<dyn ValueTransform>>,
+    // Transforms for the values of single keys, run after the ones above in key order
+    pub key_transforms: BTreeMap<String, KeyTransformFn>,
+    // Keys whose env var holds a whole section as a JSON object, eg. `database` for
+    // `APP__DATABASE={"host":"db","port":5432}`, which is merged into the config at that key
+    pub env_json_keys: Vec<String>,
+    // Suppress every diagnostic message the loader prints (defaults, deprecations, warnings), for
+    // embedders that want loading to be completely silent
+    pub quiet: bool,
+    // Process env vars that must be set, checked with require_env_vars() before anything is
+    // loaded, eg. SECRETS_ENCRYPTION_KEY in prod
+    pub required_env_vars: Vec<String>,
+    // Where config files are read from, std::fs when unset
+    pub file_provider: Option<Arc<dyn FileProvider>>,
+    // Load the same config from the same inputs regardless of the ambient env
+    pub reproducible: Option<Reproducible>,
+    // Env vars that each hold one secret, as (env var, config key) pairs, eg.
+    // `("DB_PASSWORD", "database.password")`. They are merged above the yaml secrets files
+    pub secret_env_vars: Vec<(String, String)>,
+    // For several apps sharing one config dir, eg. `app-a` so that this app only sees the
+    // `app-a.*` sub-tree, which its config structs and getters then map to without the prefix
+    pub app_namespace: Option<String>,
+    // Keys whose env vars only act as defaults, applied below every file rather than above them,
+    // eg. for fallback values provided by the platform that shouldn't override explicit config
+    pub env_fill_only_keys: Vec<String>,
+    // Fail to load when encrypted secrets files are present but SECRETS_ENCRYPTION_KEY isn't set,
+    // rather than carrying on without their secrets. Implied by SecretsMode::Required
+    pub require_key_when_enc_present: bool,
+    pub secrets_mode: SecretsMode,
+    // Merge strategies for array keys as (key, strategy) pairs, eg. `("plugins", Append)`. A layer
+    // setting the key to anything other than an array replaces what came before it
+    pub array_merge: Vec<(String, MergeStrategy)>,
+    // Keys env vars may set. When given, only the env vars for these keys (and keys under them)
+    // are read, with or without a prefix, so no stray env var can influence the config. This is
+    // the recommended mode for multi-tenant hosts, where the process env isn't fully ours
+    pub env_allowlist: Option<Vec<String>>,
+    // Pins the environment, so ENV is never read. Without it, ENV is read again on every reload()
+    pub environment: Option<Environment>,
+}
+
+impl InitOptions {
+    fn files(&self) -> &dyn FileProvider {
+        self.file_provider.as_deref().unwrap_or(&StdFs)
+    }
+
+    fn env_file_suffix(&self) -> &str {
+        self.env_file_suffix.as_deref().unwrap_or(".env")
+    }
+
+    // Every env var prefix besides `prefix` itself
+    fn other_env_prefixes(&self) -> impl Iterator<Item = &String> {
+        self.fallback_prefixes
+            .iter()
+            .chain(self.env_prefix_map.iter().map(|(prefix, _)| prefix))
+    }
+}
+
+struct Loaded {
+    config: config::Config,
+    env: Environment,
+    info: LoadInfo,
+    // Values deserialized from this config by arc_load(), by type. A reload swaps in a new Loaded
+    // with an empty cache, so a stale value is never handed out
+    cache: RwLock<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+// What the loader found while assembling the sources
+#[derive(Clone, Default)]
+struct LoadInfo {
+    config_dir: PathBuf,
+    // Each decrypted file along with the index of the key that decrypted it
+    decrypted: Vec<(PathBuf, usize)>,
+    secrets: SecretsStatus,
+    // The keys from SECRETS_ENCRYPTION_KEY, for decrypting `!secret` values in files reloaded
+    // with reload_file(). Empty with SecretsMode::Disabled
+    secrets_keys: Vec<String>,
+    // Every source, from lowest to highest precedence
+    layers: Vec<Layer>,
+    // The manifest from CONFIG_MANIFEST, which reload_file() checks the file against too
+    manifest: Option<Arc<Manifest>>,
+    // The config keys set by vars from encrypted .env files
+    env_secret_keys: Vec<String>,
+    // The paths (as given by tree::visit_leaves_mut()) of the values that came from secrets files,
+    // encrypted .env files, `!secret` values or Vault, under their final keys
+    secret_paths: BTreeSet<String>,
+    #[cfg(feature = "secret-telemetry")]
+    secret_keys: BTreeSet<String>,
+}
+
+impl LoadInfo {
+    fn decrypt(
+        &mut self,
+        path: String,
+        keys: &[String],
+        files: &dyn FileProvider,
+    ) -> Option<Vec<u8>> {
+        let stamp = files.modified(Path::new(&path)).ok().flatten();
+        let decrypted = match stamp.and_then(|stamp| secrets::cached(&path, stamp, keys)) {
+            Some(decrypted) => Some(decrypted),
+            None => {
+                let contents = match files.read(Path::new(&path)) {
+                    Ok(contents) => contents,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+                    Err(_) => {
+                        self.secrets.files.push((path.into(), false));
+                        return None;
+                    }
+                };
+                secrets::decrypt(&path, &contents, keys, stamp, files)
+            }
+        };
+        let Some((decrypted, key_index)) = decrypted else {
+            self.secrets.files.push((path.into(), false));
+            return None;
+        };
+        self.secrets.files.push((path.clone().into(), true));
+        self.decrypted.push((path.into(), key_index));
+        Some(decrypted)
+    }
+}
+
+// How secrets files are handled, so the same binary can be deployed both where it handles secrets
+// and where it never should
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SecretsMode {
+    // Secrets files are loaded when SECRETS_ENCRYPTION_KEY is set, and skipped otherwise
+    #[default]
+    Auto,
+    // Secrets files (encrypted or not) and secret_env_vars are never read, and `!secret` values
+    // and Vault references in other files are skipped, whether or not a key is set
+    Disabled,
+    // Loading fails unless the key is set, and encrypted secrets files are found and all of them
+    // decrypt (see SecretsStatus::loaded())
+    Required,
+}
+
+// How an array is merged across the layers that set it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeStrategy {
+    // The highest precedence layer's array replaces the others (the usual behaviour)
+    #[default]
+    Replace,
+    // The arrays of every layer are concatenated, from lowest to highest precedence
+    Append,
+    // Like Append, but keeping only the first occurrence of each value
+    Unique,
+}
+
+// Whether the encrypted secrets files could be loaded, eg. for a startup gate or health check
+#[derive(Debug, Clone, Default)]
+pub struct SecretsStatus {
+    // Whether SECRETS_ENCRYPTION_KEY was set
+    pub key_present: bool,
+    // Every encrypted file found, along with whether it decrypted
+    pub files: Vec<(PathBuf, bool)>,
+}
+
+impl SecretsStatus {
+    // True when the key was set, and at least one encrypted file was found and all of them
+    // decrypted
+    pub fn loaded(&self) -> bool {
+        self.key_present && !self.files.is_empty() && self.files.iter().all(|(_, ok)| *ok)
+    }
+}
+
+// Everything needed to run the load again on reload()
+struct Init {
+    options: InitOptions,
+    customize: Box<dyn Fn(ConfigBuilder) -> ConfigBuilder + Send + Sync>,
+    // Config given directly at init rather than read from files, below everything else
+    base: Option<Layer>,
+    // For validate_at_build(), whose load depends on nothing but the files: there's no manifest
+    // to check, and `vault:` values are left as they are
+    build_script: bool,
+}
+
+// The environment used when ENV is unset. Defaults to dev, and can be baked in at build time, eg.
+// `DEFAULT_ENV=prod cargo build --release` so that release builds default to prod
+const DEFAULT_ENV: &str = match option_env!("DEFAULT_ENV") {
+    Some(env) => env,
+    None => "dev",
+};
+
+const DEFAULT_PLACEHOLDER: &str = "CHANGE_ME";
+
+static INIT: OnceLock<Init> = OnceLock::new();
+
+static CONFIG: OnceLock<RwLock<Arc<Loaded>>> = OnceLock::new();
+
+// Overrides given to apply_args(), in the order they were given
+static ARGS: Mutex<Vec<(String, config::Value)>> = Mutex::new(Vec::new());
+
+// Partial configs given to apply_overlay(), in the order they were given
+static OVERLAYS: Mutex<Vec<Layer>> = Mutex::new(Vec::new());
+
+// Receivers handed out by subscribe(). Senders whose receiver was dropped are removed on the next
+// swap
+static SUBSCRIBERS: Mutex<Vec<mpsc::Sender<Arc<config::Config>>>> = Mutex::new(Vec::new());
+
+// Snapshot of the process env taken by freeze()
+static FROZEN_ENV: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+pub type ConfigBuilder = config::ConfigBuilder<config::builder::DefaultState>;
 
 pub fn init_default() {
-    CONFIG.get_or_init(|| read_config_vars_from_all_sources(None, vec![]).unwrap());
+    init_with_options(InitOptions::default());
 }
 
 pub fn init(prefix: Option<String>, list_parse_keys: Vec<String>) {
-    CONFIG.get_or_init(|| read_config_vars_from_all_sources(prefix, list_parse_keys).unwrap());
+    init_with_options(InitOptions {
+        prefix,
+        list_parse_keys,
+        ..Default::default()
+    });
 }
 
-// Order of precedence (highest to lowest):
-// 1. Env vars
-// 2. local.env / local-secrets.env.enc
-// 3. <env>.env / <env>-secrets.env.enc
-// 4. default.env / default-secrets.env.enc
-// 5. local.yaml / local-secrets.yaml.enc
-// 6. <env>.yaml / <env>-secrets.yaml.enc
-// 7. default.yaml / default-secrets.yaml.enc
-fn read_config_vars_from_all_sources(
+pub fn init_with_options(options: InitOptions) {
+    init_once(Init {
+        options,
+        customize: Box::new(|builder| builder),
+        base: None,
+        build_script: false,
+    });
+}
+
+// Like init(), along with the key/value rows of a SQLite table
+#[cfg(feature = "sqlite")]
+pub fn init_with_db_source(
+    db_source: DbSource,
     prefix: Option<String>,
     list_parse_keys: Vec<String>,
-) -> Result<config::Config> {
-    let config_dir = env::var("CONFIG_DIR").unwrap_or_else(|_| {
-        println!("CONFIG_DIR is not set, defaulting to config in the same folder");
-        "./conf".into()
+) {
+    init_with_options(InitOptions {
+        prefix,
+        list_parse_keys,
+        db_source: Some(db_source),
+        ..Default::default()
+    });
+}
+
+// Escape hatch for anything the loader doesn't support directly: the standard source stack is
+// built as usual (env vars included), then handed to `f` for final customization before building.
+// `f` runs again on every reload()
+pub fn init_with_builder(
+    f: impl Fn(ConfigBuilder) -> ConfigBuilder + Send + Sync + 'static,
+    prefix: Option<String>,
+    list_parse_keys: Vec<String>,
+) {
+    init_once(Init {
+        options: InitOptions {
+            prefix,
+            list_parse_keys,
+            ..Default::default()
+        },
+        customize: Box::new(f),
+        base: None,
+        build_script: false,
     });
+}
 
-    let env = env::var("ENV").unwrap_or_else(|_| {
-        println!("ENV is not set, defaulting to dev environment");
-        "dev".into()
+// Uses a config built elsewhere (eg. by a bootstrap phase with its own source stack, or a test) as
+// is, so LoadConfig and the accessors work against it without any files or env vars being read.
+// The environment is still taken from ENV, and an invalid ENV is an error. Since there are no
+// sources to re-read, reload(), set_persisted() and the other functions that re-read them return
+// an error
+pub fn init_with_config(config: config::Config) -> Result<()> {
+    let env = env_from_process_env(&InitOptions::default())?;
+    CONFIG.get_or_init(|| {
+        RwLock::new(Arc::new(Loaded {
+            config,
+            env,
+            info: LoadInfo::default(),
+            cache: Default::default(),
+        }))
     });
-    let env = Environment::from_str(&env).expect("Invalid value for ENV");
+    Ok(())
+}
 
-    // dotenvy::from_path does NOT override existing env vars
-    // So loading in this order ensures that pre-existing env vars take precedence,
-    // while env vars in the files override each other in the appropriate order
-    dotenvy::from_path(format!("{config_dir}/.env")).ok();
-    dotenvy::from_path(format!("{config_dir}/local.env")).ok();
-    dotenvy::from_path(format!("{config_dir}/{env}.env")).ok();
-    dotenvy::from_path(format!("{config_dir}/default.env")).ok();
+// Uses `text` as the lowest precedence source, with the usual files and env vars layered on top,
+// eg. for config generated by another tool
+pub fn init_from_str(
+    text: &str,
+    format: FileFormat,
+    prefix: Option<String>,
+    list_parse_keys: Vec<String>,
+) {
+    init_with_base("<string>", text, format, prefix, list_parse_keys);
+}
+
+// Like init_from_str() with the entire contents of `reader`, eg. stdin for config piped in from
+// another tool
+pub fn init_from_reader(
+    mut reader: impl Read,
+    format: FileFormat,
+    prefix: Option<String>,
+    list_parse_keys: Vec<String>,
+) {
+    let mut text = String::new();
+    reader
+        .read_to_string(&mut text)
+        .expect("Failed to read config");
+    init_with_base("<reader>", &text, format, prefix, list_parse_keys);
+}
+
+fn init_with_base(
+    name: &str,
+    text: &str,
+    format: FileFormat,
+    prefix: Option<String>,
+    list_parse_keys: Vec<String>,
+) {
+    let source = config::File::from_str(text, format);
+    init_once(Init {
+        options: InitOptions {
+            prefix,
+            list_parse_keys,
+            ..Default::default()
+        },
+        customize: Box::new(|builder| builder),
+        base: Some(Layer::new(name, LayerKind::File, source)),
+        build_script: false,
+    });
+}
+
+fn init_once(init: Init) {
+    CONFIG.get_or_init(|| {
+        let init = INIT.get_or_init(|| init);
+        RwLock::new(Arc::new(load_with_timeout(init, runtime_layers()).unwrap()))
+    });
+}
+
+// Re-reads every source with the options given at init and swaps in the result. Vars that .env
+// files exported to the process env are read from the files again, unless something else has
+// changed them in the process env since
+// ENV is read again too, so a running process can switch environments by changing it and
+// reloading. An environment pinned at init (with InitOptions::environment, or the reproducible
+// environment) always wins over the live ENV
+pub fn reload() -> Result<()> {
+    let init = INIT.get().ok_or_else(not_initialized)?;
+    warn_if_env_changed(&init.options);
+    let loaded = load_with_timeout(init, runtime_layers())?;
+    swap_in(loaded);
+    Ok(())
+}
 
-    let secrets_encryption_key_b64 = env::var("SECRETS_ENCRYPTION_KEY").ok();
-    if secrets_encryption_key_b64.is_none() {
-        println!("SECRETS_ENCRYPTION_KEY not found, not loading encrypted secrets");
+// Re-reads just the yaml file `name` (eg. `features.yaml`) from the config dirs and merges it
+// again with every other source as it was last loaded, which is lighter than a full reload().
+// Only plaintext yaml files that were present at the last load can be reloaded this way
+pub fn reload_file(name: &str) -> Result<()> {
+    let init = INIT.get().ok_or_else(not_initialized)?;
+    if init.options.yaml_cross_file_anchors {
+        bail!("reload_file() can't be used with yaml_cross_file_anchors, use reload() instead");
+    }
+    let loaded = current()?;
+    let mut info = loaded.info.clone();
+    let manifest = info.manifest.clone();
+    let verified_files;
+    let files: &dyn FileProvider = match manifest {
+        Some(ref manifest) => {
+            verified_files = VerifiedFiles::new(init.options.files(), manifest);
+            &verified_files
+        }
+        None => init.options.files(),
+    };
+    let suffix = format!("/{name}");
+    let indices: Vec<usize> = (0..info.layers.len())
+        .filter(|&i| {
+            let layer = &info.layers[i];
+            layer.kind != LayerKind::Env
+                && layer.name.ends_with(&suffix)
+                && !layer.name.ends_with(".enc")
+        })
+        .collect();
+    if indices.is_empty() {
+        bail!("{name} was not loaded as a plaintext yaml file, use reload() instead");
     }
 
-    if let Some(ref key) = secrets_encryption_key_b64 {
-        if let Ok(decrypted) = decrypt_file(&format!("{config_dir}/{env}-secrets.env.enc"), key) {
-            dotenvy::from_read(decrypted.as_slice())?;
-        } else {
-            // println!("Couldn't find or failed to decrypt
-            // {env}-secrets.env.enc, not loading encrypted secrets");
+    // From the back, so removing a layer doesn't shift the ones still to go
+    for i in indices.into_iter().rev() {
+        let layer = &info.layers[i];
+        let document = yaml::Document::read(files, layer.name.clone(), layer.kind)?;
+        match document {
+            Some(mut document) => {
+                document.text =
+                    secrets::decrypt_inline(&document.path, &document.text, &info.secrets_keys)?;
+                info.layers[i] =
+                    yaml::layers(vec![document], false, &init.options.yaml_limits)?.remove(0);
+            }
+            None => {
+                info.layers.remove(i);
+            }
         }
     }
+    let loaded = finish(init, builder_from(&info.layers), loaded.env, info)?;
+    swap_in(loaded);
+    Ok(())
+}
 
-    let mut config_builder = config::Config::builder()
-        // Start off by merging in the "default" configuration file
-        .add_source(
-            config::File::new(&format!("{config_dir}/default.yaml"), FileFormat::Yaml)
-                .required(false),
-        )
-        // Add in the current environment file
-        .add_source(
-            config::File::new(&format!("{config_dir}/{env}"), FileFormat::Yaml).required(false),
-        )
-        // Add in the secrets file for the current environment, which might be used as plaintext
-        // during local development. This file shouldn't be checked in to git
-        .add_source(
-            config::File::new(
-                &format!("{config_dir}/{env}-secrets.yaml"),
-                FileFormat::Yaml,
-            )
-            .required(false),
+fn swap_in(loaded: Loaded) {
+    let config = Arc::new(loaded.config.clone());
+    *CONFIG.get().unwrap().write().unwrap() = Arc::new(loaded);
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|sender| sender.send(config.clone()).is_ok());
+}
+
+// Receives the new config after every successful reload(), reload_file(), apply_args(),
+// apply_overlay() or set_persisted(), so a subsystem can react to changes without polling. Every
+// call returns an independent receiver, and changes made before it was called aren't sent
+pub fn subscribe() -> mpsc::Receiver<Arc<config::Config>> {
+    let (sender, receiver) = mpsc::channel();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+// Errors listing every one of `vars` that isn't set in the process env, to guard the bootstrap env
+// vars that the loader itself depends on. Vars from .env files don't count
+pub fn require_env_vars(vars: &[impl AsRef<str>]) -> Result<()> {
+    let missing: Vec<&str> = vars
+        .iter()
+        .map(|var| var.as_ref())
+        .filter(|var| env::var_os(var).is_none())
+        .collect();
+    if !missing.is_empty() {
+        bail!("Required env vars are not set: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+// Merges `key=value` overrides (eg. collected from `--set a.b=1 --set a.c=2`) on top of every
+// other source, then reloads. Keys are dotted paths, values are coerced to bools and numbers where
+// they parse as one, and a key given several times becomes a list. The overrides stay in place
+// across reloads, and later calls win over earlier ones. Overrides that fail to load are dropped
+pub fn apply_args(args: &[String]) -> Result<()> {
+    let parsed = args::parse(args)?;
+    let init = INIT.get().ok_or_else(not_initialized)?;
+    warn_if_env_changed(&init.options);
+    let mut committed = ARGS.lock().unwrap();
+    let mut candidate = committed.clone();
+    candidate.extend(parsed);
+    let mut layers: Vec<Layer> = args_layer(&candidate).into_iter().collect();
+    layers.extend(OVERLAYS.lock().unwrap().iter().cloned());
+    let loaded = load_with_timeout(init, layers).context("Failed to apply config overrides")?;
+    *committed = candidate;
+    swap_in(loaded);
+    Ok(())
+}
+
+fn args_layer(args: &[(String, config::Value)]) -> Option<Layer> {
+    if args.is_empty() {
+        return None;
+    }
+    let mut table = config::Map::new();
+    for (key, value) in args.iter() {
+        tree::set_path(&mut table, key, value.clone());
+    }
+    Some(Layer::new("args", LayerKind::File, tree::TreeSource(table)))
+}
+
+// Merges a partial config (eg. a few keys pushed by a control plane) on top of every other source,
+// including apply_args() overrides, and swaps in the result without re-reading any files. Like
+// the args, overlays stay in place across reloads, and later overlays win over earlier ones
+pub fn apply_overlay(source: &str, format: FileFormat) -> Result<()> {
+    let init = INIT.get().ok_or_else(not_initialized)?;
+    let overlay = Layer::new(
+        "overlay",
+        LayerKind::File,
+        config::File::from_str(source, format),
+    );
+    let mut overlays = OVERLAYS.lock().unwrap();
+    let loaded = current()?;
+    let mut info = loaded.info.clone();
+    info.layers.push(overlay.clone());
+    let loaded = finish(init, builder_from(&info.layers), loaded.env, info)
+        .context("Failed to apply config overlay")?;
+    overlays.push(overlay);
+    swap_in(loaded);
+    Ok(())
+}
+
+// The layers added at runtime, which sit above every other source
+fn runtime_layers() -> Vec<Layer> {
+    let mut layers: Vec<Layer> = args_layer(&ARGS.lock().unwrap()).into_iter().collect();
+    layers.extend(OVERLAYS.lock().unwrap().iter().cloned());
+    layers
+}
+
+// Snapshots the process env, so that every later reload() warns about env vars that would change
+// the config and were changed since, eg. by a stray `env::set_var` elsewhere in the program.
+// Calling it again takes a new snapshot
+pub fn freeze() {
+    *FROZEN_ENV.lock().unwrap() = Some(env::vars().collect());
+}
+
+fn warn_if_env_changed(options: &InitOptions) {
+    let frozen = FROZEN_ENV.lock().unwrap();
+    let Some(frozen) = frozen.as_ref() else {
+        return;
+    };
+    let current: HashMap<String, String> = env::vars().collect();
+    let mut changed: Vec<&String> = frozen
+        .keys()
+        .chain(current.keys())
+        .filter(|var| is_config_var(var, options) && frozen.get(*var) != current.get(*var))
+        .collect();
+    changed.sort();
+    changed.dedup();
+    if !changed.is_empty() {
+        let changed: Vec<&str> = changed.into_iter().map(String::as_str).collect();
+        diag!(
+            options,
+            "WARNING: env vars changed since freeze(), the reloaded config may differ: {}",
+            changed.join(", ")
         );
+    }
+}
 
-    if let Some(ref key) = secrets_encryption_key_b64 {
-        if let Ok(decrypted) = decrypt_file(&format!("{config_dir}/{env}-secrets.yaml.enc"), key) {
-            config_builder = config_builder.add_source(
-                config::File::from_str(&String::from_utf8(decrypted)?, FileFormat::Yaml)
-                    .required(false),
-            );
+// Without a prefix, any env var may set a config key
+fn is_config_var(var: &str, options: &InitOptions) -> bool {
+    const LOADER_VARS: [&str; 5] = [
+        "CONFIG_DIR",
+        "ENV",
+        "SECRETS_ENCRYPTION_KEY",
+        "CONFIG_MANIFEST",
+        "PROFILES",
+    ];
+    let has_prefix = |prefix: &String| {
+        var.to_lowercase()
+            .starts_with(&format!("{}__", prefix.to_lowercase()))
+    };
+    LOADER_VARS.contains(&var)
+        || options.other_env_prefixes().any(has_prefix)
+        || match &options.prefix {
+            Some(prefix) => has_prefix(prefix),
+            None => true,
+        }
+}
+
+// `runtime_layers` go on top of every other source
+fn load_with_timeout(init: &'static Init, runtime_layers: Vec<Layer>) -> Result<Loaded> {
+    let Some(timeout) = init.options.init_timeout else {
+        return read_config_vars_from_all_sources(init, runtime_layers);
+    };
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(read_config_vars_from_all_sources(init, runtime_layers));
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(loaded) => loaded,
+        Err(mpsc::RecvTimeoutError::Timeout) => bail!("Loading config timed out after {timeout:?}"),
+        Err(mpsc::RecvTimeoutError::Disconnected) => bail!("Loading config panicked"),
+    }
+}
+
+fn not_initialized() -> anyhow::Error {
+    if CONFIG.get().is_some() {
+        return anyhow!("Config was given to init_with_config(), so it has no sources to re-read");
+    }
+    anyhow!("Config is not initialized, call init() first")
+}
+
+fn current() -> Result<Arc<Loaded>> {
+    let loaded = CONFIG.get().ok_or_else(not_initialized)?;
+    Ok(loaded.read().unwrap().clone())
+}
+
+// The environment the config was loaded for
+pub fn environment() -> Environment {
+    current().unwrap().env
+}
+
+pub fn is_dev() -> bool {
+    environment() == Environment::Dev
+}
+
+pub fn is_stag() -> bool {
+    environment() == Environment::Stag
+}
+
+pub fn is_prod() -> bool {
+    environment() == Environment::Prod
+}
+
+pub fn is_production() -> bool {
+    environment().is_production()
+}
+
+// The resolved config dir (canonicalized where it exists) that the files were read from
+pub fn config_dir() -> PathBuf {
+    current().unwrap().info.config_dir.clone()
+}
+
+// Which of the comma-separated SECRETS_ENCRYPTION_KEY keys decrypted each encrypted file, to
+// confirm everything decrypts with a new key before the old one is removed
+pub fn decryption_report() -> Vec<(PathBuf, usize)> {
+    current().unwrap().info.decrypted.clone()
+}
+
+// The table at `key` as a map with keys parsed from their string form, eg. `HashMap<u32, Limit>`
+// for a table keyed by integer tiers, since config keys are always strings
+pub fn get_map<K, V>(key: &str) -> Result<HashMap<K, V>>
+where
+    K: FromStr + Eq + std::hash::Hash,
+    K::Err: std::fmt::Display,
+    V: DeserializeOwned,
+{
+    let table: HashMap<String, config::Value> = current()?.config.get(key)?;
+    let mut map = HashMap::with_capacity(table.len());
+    for (map_key, value) in table {
+        let parsed = map_key
+            .parse()
+            .map_err(|e| anyhow!("Invalid key {map_key} in {key}: {e}"))?;
+        let value = deserialize_at(value, Some(&format!("{key}.{map_key}")))?;
+        map.insert(parsed, value);
+    }
+    Ok(map)
+}
+
+// The merged config as env vars, eg. `("APP__SERVER__PORT", "8080")`, for passing on to child
+// processes that read env var config of their own. Lists are joined with commas (escaping commas
+// and backslashes in their elements), the way list keys are parsed from env vars
+pub fn to_env_vars(prefix: &str, separator: &str) -> Vec<(String, String)> {
+    let table = current().unwrap().config.collect().unwrap();
+    tree::flatten(&table)
+        .into_iter()
+        .map(|(key, value)| {
+            let var = key.replace('.', separator).to_uppercase();
+            let var = if prefix.is_empty() {
+                var
+            } else {
+                format!("{prefix}{separator}{var}")
+            };
+            let value = match value.kind {
+                config::ValueKind::Nil => String::new(),
+                config::ValueKind::Array(values) => {
+                    join_list(values.iter().map(ToString::to_string))
+                }
+                _ => value.to_string(),
+            };
+            (var, value)
+        })
+        .collect()
+}
+
+// Forgets the decrypted secrets files kept across reloads, so the next reload() decrypts them all
+// again even if they haven't changed, eg. after rotating keys
+pub fn clear_secrets_cache() {
+    secrets::clear_cache();
+}
+
+pub fn secrets_status() -> SecretsStatus {
+    current().unwrap().info.secrets.clone()
+}
+
+pub fn secrets_loaded() -> bool {
+    secrets_status().loaded()
+}
+
+// The full merged config as a nested dynamic map, for tools that don't know the schema
+pub fn as_tree() -> Result<BTreeMap<String, config::Value>> {
+    Ok(current()?.config.clone().try_deserialize()?)
+}
+
+// The value of `key` as a string whatever its type (eg. `8080` for a number), for writing config
+// into templated files or command lines
+pub fn get_string(key: &str) -> Result<String> {
+    let loaded = current()?;
+    let value: config::Value = loaded.config.get(key)?;
+    #[cfg(feature = "secret-telemetry")]
+    access::record_get(key, &loaded.info.secret_keys);
+    Ok(value.to_string())
+}
+
+// The value of `key`, or `default` when the key isn't set at all. A value that is set but doesn't
+// deserialize into `T` is still an error, so a mistyped value isn't masked by the default
+pub fn get_or<T: DeserializeOwned>(key: &str, default: T) -> Result<T> {
+    let loaded = current()?;
+    let value: config::Value = match loaded.config.get(key) {
+        Ok(value) => value,
+        Err(config::ConfigError::NotFound(_)) => return Ok(default),
+        Err(e) => return Err(e.into()),
+    };
+    #[cfg(feature = "secret-telemetry")]
+    access::record_get(key, &loaded.info.secret_keys);
+    deserialize_at(value, Some(key))
+}
+
+// Where the merged value of a key came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    // A file by path, or another source by name, eg. `args` or `overlay`
+    Source(String),
+    // An env var with the given prefix, as declared in InitOptions, or without one
+    EnvVar { prefix: Option<String> },
+}
+
+// Which source set the value of `key`, eg. to check during a prefix migration that the env vars
+// with the new prefix are the ones taking effect. Meant for keys of single values, not tables
+pub fn origin(key: &str) -> Option<Origin> {
+    let loaded = current().unwrap();
+    let value: config::Value = loaded.config.get(key).ok()?;
+    let name = value.origin()?;
+    let layer = loaded.info.layers.iter().find(|layer| layer.name == name);
+    Some(match layer.and_then(Layer::env_prefix) {
+        Some(prefix) => Origin::EnvVar {
+            prefix: prefix.map(str::to_owned),
+        },
+        None => Origin::Source(name.to_owned()),
+    })
+}
+
+// Secret keys that no `load()` or getter has read so far in this run, to find secrets that can be
+// pruned. Only yaml secrets files are tracked, and a key counts as read when any type loaded with
+// `load()` has a field for it
+#[cfg(feature = "secret-telemetry")]
+pub fn unused_secret_keys() -> Vec<String> {
+    access::unused(&current().unwrap().info.secret_keys)
+}
+
+// DANGEROUS: the plaintext of the encrypted file `name` in the config dir (eg.
+// `prod-secrets.yaml.enc`), decrypted with the keys the config was loaded with. Only meant for
+// confirming what a secrets file contains during an incident, and only built with the
+// `debug-secrets` feature, which must never be enabled in production builds
+#[cfg(feature = "debug-secrets")]
+pub fn decrypted_secret_file(name: &str) -> Result<String> {
+    let init = INIT.get().ok_or_else(not_initialized)?;
+    let loaded = current()?;
+    let path = loaded.info.config_dir.join(name);
+    let files = init.options.files();
+    let contents = files
+        .read(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let path = path.to_string_lossy();
+    let keys = &loaded.info.secrets_keys;
+    let Some((decrypted, _)) = secrets::decrypt(&path, &contents, keys, None, files) else {
+        bail!("Failed to decrypt {path} with SECRETS_ENCRYPTION_KEY");
+    };
+    String::from_utf8(decrypted).with_context(|| format!("{path} is not valid UTF-8"))
+}
+
+// Merges two configs built elsewhere, eg. a base and an overlay read from separate source sets.
+// Tables are merged key by key, and any other value in `overlay` (arrays included) replaces the
+// one in `base`. Values keep their origins
+pub fn deep_merge(base: config::Config, overlay: config::Config) -> Result<config::Config> {
+    let mut table = base.collect()?;
+    tree::merge(&mut table, overlay.collect()?);
+    tree::rebuild(table)
+}
+
+// The merged config written out as a file, eg. to snapshot exactly what ran into a deployment
+// artifact. Supports yaml and json
+pub fn serialize_effective(format: FileFormat) -> Result<String> {
+    let table = current()?.config.collect()?;
+    render::render(&table, format)
+}
+
+// Writes the merged config to `path` as yaml or json, eg. as a snapshot for the next cold start to
+// load with init_from_str() rather than running the full load and decrypting every secrets file.
+// The file is replaced atomically. With `redact`, values that came from secrets (secrets files,
+// encrypted .env files, `!secret` values or Vault) are written as `[REDACTED]`
+pub fn snapshot_to(path: &Path, format: FileFormat, redact: bool) -> Result<()> {
+    let loaded = current()?;
+    let rendered = render_snapshot(&loaded, format, redact)?;
+    persist::write_atomic(path, &rendered)
+}
+
+fn render_snapshot(loaded: &Loaded, format: FileFormat, redact: bool) -> Result<String> {
+    let mut table = loaded.config.collect()?;
+    if redact {
+        tree::visit_leaves_mut(&mut table, &mut |path, value| {
+            if is_secret_path(&loaded.info.secret_paths, path) {
+                *value = config::Value::new(None, "[REDACTED]");
+            }
+            Ok(())
+        })?;
+    }
+    render::render(&table, format)
+}
+
+// Whether the value at `path` came from a secret, or is part of an array or table that did
+fn is_secret_path(secret_paths: &BTreeSet<String>, path: &str) -> bool {
+    secret_paths
+        .iter()
+        .any(|secret| rekey(path, secret, "").is_some())
+}
+
+// `path` with its leading key `from` replaced by `to`, or `None` if it isn't `from` or under it
+fn rekey(path: &str, from: &str, to: &str) -> Option<String> {
+    let rest = path.strip_prefix(from)?;
+    if !rest.is_empty() && !rest.starts_with(['.', '[']) {
+        return None;
+    }
+    Some(format!("{to}{rest}"))
+}
+
+// SHA-256 hex digest of the merged config, eg. for every instance to report so that nodes running
+// stale or divergent config stand out. It's computed over the config rendered as json with sorted
+// keys, so it doesn't depend on the order of the sources. Values that came from secrets (the same
+// ones snapshot_to() redacts) are left out, so the hash can be shared without revealing anything
+// about them
+pub fn effective_hash() -> Result<String> {
+    let loaded = current()?;
+    let mut table = loaded.config.collect()?;
+    let secret_paths = &loaded.info.secret_paths;
+    tree::retain_leaves(&mut table, &|path, _| !is_secret_path(secret_paths, path));
+    let canonical = render::render(&table, FileFormat::Json)?;
+    Ok(format!("{:x}", sha2::Sha256::digest(canonical.as_bytes())))
+}
+
+// Order of precedence (highest to lowest):
+// 0. local-overrides.yaml (dev-only, for beating ambient env vars while debugging)
+// 1. Env vars (or below 8. with EnvPrecedence::Lowest)
+// 2. .env
+// 3. local.env / local-secrets.env.enc
+// 4. <env>.env / <env>-secrets.env.enc
+// 5. default.env / default-secrets.env.enc
+// 6. local.yaml / local-secrets.yaml.enc
+// 7. <env>.yaml / <env>-secrets.yaml.enc
+// 8. default.yaml / default-secrets.yaml.enc
+// Profile files (then conditional sources) sit between 6. and 7.
+// Within each .env level the encrypted file wins over the plaintext one
+// With a base_dir, its files are loaded in the same order below all files in CONFIG_DIR (ie. its
+// .env files sit below the CONFIG_DIR .env files, and its yaml files below CONFIG_DIR yaml files)
+// With env_dirs, the files in CONFIG_DIR/<env>/ are likewise loaded above those in CONFIG_DIR
+// A db_source table sits below 8., above 6. or above 1. per its DbPrecedence (.env files are read
+// as env vars, so a table above 6. is still below them)
+// A source given to init_from_str() or init_from_reader() sits below all of these
+// The loader's own env vars (ENV, CONFIG_DIR, SECRETS_ENCRYPTION_KEY and PROFILES) can also be set
+// in a loader.toml in the working dir, eg. `env = "prod"`, with the process env winning over it
+// and overrides given to apply_args() sit above all of them, with apply_overlay() overlays above
+// those
+fn read_config_vars_from_all_sources(init: &Init, runtime_layers: Vec<Layer>) -> Result<Loaded> {
+    require_env_vars(&init.options.required_env_vars)?;
+    let files = init.options.files();
+    let config_dir = prelude::var("CONFIG_DIR", files)?.unwrap_or_else(|| {
+        diag!(
+            init.options,
+            "CONFIG_DIR is not set, defaulting to config in the same folder"
+        );
+        "./conf".into()
+    });
+
+    let Some(ref reproducible) = init.options.reproducible else {
+        let env = match init.options.environment {
+            Some(env) => env,
+            None => env_from_process_env(&init.options)?,
+        };
+        // ENV and CONFIG_DIR from loader.toml have been read above, the rest are read like vars
+        // from .env files, which never override the process env
+        let mut vars = Vars::from_process_env(true);
+        let settings = prelude::read(files)?
+            .into_iter()
+            .filter(|(var, _)| var != "ENV" && var != "CONFIG_DIR");
+        vars.load(settings.map(Ok))?;
+        return load(init, config_dir, env, vars, runtime_layers);
+    };
+    let prefixes: Vec<String> = match (&init.options.prefix, reproducible.prefixed_env_vars) {
+        (Some(prefix), true) => [prefix]
+            .into_iter()
+            .chain(init.options.other_env_prefixes())
+            .map(|prefix| format!("{}__", prefix.to_lowercase()))
+            .collect(),
+        _ => vec![],
+    };
+    let mut vars = Vars::from_process_env(true);
+    vars.retain(|var| {
+        prefixes
+            .iter()
+            .any(|prefix| var.to_lowercase().starts_with(prefix))
+    });
+    load(init, config_dir, reproducible.env, vars, runtime_layers)
+}
+
+fn env_from_process_env(options: &InitOptions) -> Result<Environment> {
+    let env = match prelude::var("ENV", options.files())? {
+        Some(env) => env,
+        None if options.forbid_default_env => {
+            bail!("ENV is not set, and defaulting to {DEFAULT_ENV} is forbidden")
         }
-        if let Ok(decrypted) = decrypt_file(&format!("{config_dir}/local-secrets.yaml.enc"), key) {
-            config_builder = config_builder.add_source(
-                config::File::from_str(&String::from_utf8(decrypted)?, FileFormat::Yaml)
-                    .required(false),
+        None => {
+            diag!(
+                options,
+                "ENV is not set, defaulting to {DEFAULT_ENV} environment"
             );
+            DEFAULT_ENV.into()
         }
     };
+    Environment::from_str(&env).map_err(|_| anyhow!("Invalid value for ENV: {env}"))
+}
 
-    config_builder = config_builder
-        // Add in a local configuration file
-        // This file shouldn't be checked in to git
-        // Note that this file is _optional_
-        .add_source(
-            config::File::new(&format!("{config_dir}/local.yaml"), FileFormat::Yaml)
-                .required(false),
-        );
+// `args` goes on top of every other source
+fn load(
+    init: &Init,
+    config_dir: String,
+    env: Environment,
+    mut vars: Vars,
+    runtime_layers: Vec<Layer>,
+) -> Result<Loaded> {
+    let (config_builder, mut info) = builder_with_all_sources(init, config_dir, env, &mut vars)?;
+    let mut config_builder = config_builder;
+    for layer in runtime_layers {
+        info.layers.push(layer.clone());
+        config_builder = config_builder.add_source(layer);
+    }
+    finish(init, config_builder, env, info)
+}
 
-    let mut env_source = if let Some(prefix) = prefix {
-        config::Environment::with_prefix(&prefix).prefix_separator("__")
-        // .convert_case(Case::Lower)
-    } else {
-        config::Environment::default()
-        // .convert_case(Case::Lower)
+fn builder_from(layers: &[Layer]) -> ConfigBuilder {
+    layers
+        .iter()
+        .fold(config::Config::builder(), |config_builder, layer| {
+            config_builder.add_source(layer.clone())
+        })
+}
+
+// Only the files listed for reproducible loads
+fn allowed_files<'a>(
+    files: &'a dyn FileProvider,
+    options: &'a InitOptions,
+) -> Option<files::AllowedFiles<'a>> {
+    let reproducible = options.reproducible.as_ref()?;
+    Some(files::AllowedFiles {
+        files,
+        names: &reproducible.files,
+    })
+}
+
+// Runs the value transforms, with `!file` values read the way the config files were: only the
+// files listed for reproducible loads, and each one verified against the manifest
+fn apply_transforms(
+    config: config::Config,
+    info: &LoadInfo,
+    options: &InitOptions,
+) -> Result<config::Config> {
+    let allowed_files = allowed_files(options.files(), options);
+    let files: &dyn FileProvider = match allowed_files {
+        Some(ref allowed_files) => allowed_files,
+        None => options.files(),
+    };
+    let verified_files = info
+        .manifest
+        .as_deref()
+        .map(|manifest| VerifiedFiles::new(files, manifest));
+    let files: &dyn FileProvider = match verified_files {
+        Some(ref verified_files) => verified_files,
+        None => files,
+    };
+    let config = transform::apply(config, &info.config_dir, files, options)?;
+    if let Some(ref verified_files) = verified_files {
+        verified_files.check()?;
     }
-    .separator("__");
-    // We have to hardcode the list of config vars across the entire application
-    // that must be parsed as Vec<String> rather than String
-    if !list_parse_keys.is_empty() {
-        env_source = env_source.list_separator(",").try_parsing(true);
-        for key in list_parse_keys {
-            env_source = env_source.with_list_parse_key(&key);
+    Ok(config)
+}
+
+// Builds the config from the assembled sources, and runs every pass over the merged values
+fn finish(
+    init: &Init,
+    config_builder: ConfigBuilder,
+    env: Environment,
+    mut info: LoadInfo,
+) -> Result<Loaded> {
+    let config = (init.customize)(config_builder).build()?;
+    let config = merge_arrays(config, &info.layers, &init.options)?;
+    let (config, mut secret_paths) = secrets::take_decrypted(config)?;
+    secret_paths.extend(info.env_secret_keys.iter().cloned());
+    for layer in &info.layers {
+        if layer.kind == LayerKind::Secret {
+            secret_paths.extend(layer.values()?.into_keys());
         }
     }
-    // Add in settings from the environment (with a prefix of <prefix>)
-    // Eg.. `AST__DEBUG=1 ./target/server` would set the `debug` key
-    config_builder = config_builder.add_source(env_source);
+    // Secrets keep being tracked under the keys they're renamed to
+    for (old, new) in &init.options.key_renames {
+        let renamed: Vec<String> = secret_paths
+            .iter()
+            .filter_map(|path| rekey(path, old, new))
+            .collect();
+        secret_paths.extend(renamed);
+    }
+    let config = apply_key_renames(config, &init.options)?;
+    check_secret_only_keys(&config, &info.layers, &secret_paths, &init.options)?;
+    check_secret_overrides(&info.layers, &init.options)?;
+    #[cfg(feature = "vault")]
+    let config = if init.build_script {
+        config
+    } else if init.options.secrets_mode == SecretsMode::Disabled {
+        vault::skip(config)?
+    } else {
+        vault::resolve(config, &mut secret_paths)?
+    };
+    let config = apply_transforms(config, &info, &init.options)?;
+    let config = transform::apply_key_transforms(config, &init.options)?;
+    check_placeholders(&config, &init.options)?;
+    let config = match init.options.app_namespace {
+        Some(ref namespace) => {
+            let prefix = format!("{namespace}.");
+            secret_paths = secret_paths
+                .iter()
+                .filter_map(|path| path.strip_prefix(&prefix).map(str::to_owned))
+                .collect();
+            scope_to_namespace(config, namespace)?
+        }
+        None => config,
+    };
+    check_allowed_sections(&config, &init.options)?;
+    info.secret_paths = secret_paths;
 
-    Ok(config_builder.build()?)
+    Ok(Loaded {
+        config,
+        env,
+        info,
+        cache: Default::default(),
+    })
 }
 
-pub trait LoadConfig: DeserializeOwned {
-    fn load() -> Self {
-        CONFIG.get().unwrap().clone().try_deserialize().unwrap()
+fn builder_with_all_sources(
+    init: &Init,
+    config_dir: String,
+    env: Environment,
+    vars: &mut Vars,
+) -> Result<(ConfigBuilder, LoadInfo)> {
+    let options = &init.options;
+    let base = init.base.as_ref();
+    let mut info = LoadInfo::default();
+    let files = options.files();
+
+    // Catches eg. CONFIG_DIR=./conf/dev.yaml, which would otherwise just load no files
+    match files.is_dir(Path::new(&config_dir)) {
+        Ok(true) => {}
+        Ok(false) => bail!("CONFIG_DIR '{config_dir}' is not a directory"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => diag!(
+            options,
+            "CONFIG_DIR '{config_dir}' does not exist, no config files will be loaded"
+        ),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read CONFIG_DIR '{config_dir}'"))
+        }
+    }
+
+    // Like ENV, PROFILES can't be set from .env files
+    let profile_files: Vec<String> = match options.profiles {
+        Some(ref profiles) => profiles.clone(),
+        None => vars
+            .get("PROFILES")
+            .map(|profiles| {
+                profiles
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|profile| !profile.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+    .iter()
+    .map(|profile| format!("profile-{profile}.yaml"))
+    .collect();
+
+    // The manifest path is only read from the process env (leaving out vars that earlier loads
+    // exported from .env files), so a tampered config bundle can't point the loader at a manifest
+    // of its own
+    let manifest = match vars::process_var("CONFIG_MANIFEST") {
+        Some(path) if !init.build_script => Some(Manifest::read(&path, &config_dir, files)?),
+        _ => None,
+    }
+    .map(Arc::new);
+    info.manifest = manifest.clone();
+    let allowed_files = allowed_files(files, options);
+    let files: &dyn FileProvider = match allowed_files {
+        Some(ref allowed_files) => allowed_files,
+        None => files,
+    };
+    let verified_files = manifest
+        .as_deref()
+        .map(|manifest| VerifiedFiles::new(files, manifest));
+    let files: &dyn FileProvider = match verified_files {
+        Some(ref verified_files) => verified_files,
+        None => files,
+    };
+
+    // Config dirs from highest to lowest precedence. The shared base dir usually lives outside
+    // the service's tree, so it's resolved to an absolute path with any symlinks followed
+    let mut dirs = vec![config_dir.clone()];
+    if options.env_dirs && !options.defaults_only {
+        let env_dir = format!("{config_dir}/{env}");
+        if !matches!(files.is_dir(Path::new(&env_dir)), Ok(true)) {
+            if options.require_env_files {
+                bail!("Config dir '{env_dir}' for the {env} environment not found");
+            }
+            diag!(
+                options,
+                "Config dir '{env_dir}' for the {env} environment not found"
+            );
+        }
+        dirs.insert(0, env_dir);
+    }
+    if let Some(ref base_dir) = options.base_dir {
+        let base_dir = files
+            .canonicalize(base_dir)
+            .with_context(|| format!("Base config dir {} not found", base_dir.display()))?;
+        dirs.push(base_dir.to_string_lossy().into_owned());
+    }
+
+    for name in &options.required_files {
+        let path = format!("{config_dir}/{name}");
+        match files.read(Path::new(&path)) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                bail!("Required config file {path} not found")
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {path}")),
+        }
+    }
+    if options.require_env_files && !options.env_dirs && !options.defaults_only {
+        let suffix = options.env_file_suffix();
+        let names = [
+            format!("{env}.yaml"),
+            format!("{env}.yml"),
+            format!("{env}-secrets.yaml"),
+            format!("{env}-secrets.yaml.enc"),
+            format!("{env}{suffix}"),
+            format!("{env}-secrets{suffix}.enc"),
+        ];
+        let found = dirs.iter().any(|dir| {
+            names
+                .iter()
+                .any(|name| files.read(Path::new(&format!("{dir}/{name}"))).is_ok())
+        });
+        if !found {
+            bail!(
+                "No config files for the {env} environment found in {}",
+                dirs.join(", ")
+            );
+        }
+    }
+
+    // Every .env file level from highest to lowest precedence, as the plaintext file's vars
+    // along with the path of its encrypted counterpart. The plaintext files are read up front
+    // since SECRETS_ENCRYPTION_KEY itself may be set in one of them
+    let suffix = options.env_file_suffix();
+    let mut env_files = vec![];
+    for dir in &dirs {
+        for (name, secrets_name) in [
+            (suffix.to_owned(), None),
+            (
+                format!("local{suffix}"),
+                Some(format!("local-secrets{suffix}.enc")),
+            ),
+            (
+                format!("{env}{suffix}"),
+                Some(format!("{env}-secrets{suffix}.enc")),
+            ),
+            (
+                format!("default{suffix}"),
+                Some(format!("default-secrets{suffix}.enc")),
+            ),
+        ] {
+            if options.defaults_only && name != format!("default{suffix}") {
+                continue;
+            }
+            let plain = read_env_file(&format!("{dir}/{name}"), files, options)?;
+            env_files.push((plain, secrets_name.map(|name| format!("{dir}/{name}"))));
+        }
+    }
+
+    let secrets_keys = vars
+        .get("SECRETS_ENCRYPTION_KEY")
+        .map(str::to_owned)
+        .or_else(|| {
+            env_files
+                .iter()
+                .flat_map(|(plain, _)| plain)
+                .find(|(key, _)| key == "SECRETS_ENCRYPTION_KEY")
+                .map(|(_, value)| value.clone())
+        })
+        .map(|keys| secrets::parse_keys(&keys))
+        .unwrap_or_default();
+    let secrets_enabled = options.secrets_mode != SecretsMode::Disabled;
+    if secrets_enabled && secrets_keys.is_empty() {
+        diag!(
+            options,
+            "SECRETS_ENCRYPTION_KEY not found, not loading encrypted secrets"
+        );
+    }
+    info.secrets.key_present = !secrets_keys.is_empty();
+    if secrets_enabled {
+        info.secrets_keys = secrets_keys.clone();
+    }
+
+    // Vars from .env files do NOT override existing env vars
+    // So loading in this order ensures that pre-existing env vars take precedence,
+    // while env vars in the files override each other in the appropriate order. Within a level
+    // the encrypted file is loaded first, so it wins over the plaintext one
+    for (plain, secrets_path) in env_files {
+        if let Some(path) = secrets_path.filter(|_| secrets_enabled) {
+            if let Some(decrypted) = info.decrypt(path.clone(), &secrets_keys, files) {
+                let text = files::text(&path, decrypted)?;
+                vars.load_secrets(dotenvy::from_read_iter(text.as_bytes()))?;
+            }
+        }
+        vars.load(plain.into_iter().map(Ok))?;
+    }
+
+    let conditional_files: Vec<&str> = options
+        .conditional_sources
+        .iter()
+        .filter(|source| (source.predicate)())
+        .map(|source| source.file_name.as_str())
+        .collect();
+    let mut documents = vec![];
+    for dir in dirs.iter().rev() {
+        documents.extend(yaml_documents(
+            dir,
+            (!options.defaults_only).then_some(&env),
+            &profile_files,
+            &conditional_files,
+            secrets_enabled.then_some(secrets_keys.as_slice()),
+            files,
+            &mut info,
+        )?);
+    }
+    if options.secrets_mode == SecretsMode::Required && !info.secrets.loaded() {
+        bail!(
+            "Secrets are required, but {}",
+            if !info.secrets.key_present {
+                "SECRETS_ENCRYPTION_KEY is not set"
+            } else if info.secrets.files.is_empty() {
+                "no encrypted secrets files were found"
+            } else {
+                "not every encrypted secrets file could be decrypted"
+            }
+        );
+    }
+    if options.require_key_when_enc_present
+        && secrets_keys.is_empty()
+        && !info.secrets.files.is_empty()
+    {
+        let paths: Vec<String> = info
+            .secrets
+            .files
+            .iter()
+            .map(|(path, _)| path.display().to_string())
+            .collect();
+        bail!(
+            "SECRETS_ENCRYPTION_KEY is not set, but encrypted secrets files are present: {}",
+            paths.join(", ")
+        );
+    }
+    info.layers = yaml::layers(
+        documents,
+        options.yaml_cross_file_anchors,
+        &options.yaml_limits,
+    )?;
+    if let Some(base) = base {
+        info.layers.insert(0, base.clone());
+    }
+    // An unreadable database only costs the values it would have set, so we carry on with files
+    #[cfg(feature = "sqlite")]
+    let mut db_layer =
+        options
+            .db_source
+            .as_ref()
+            .and_then(|db_source| match db::layer(db_source) {
+                Ok(layer) => Some((db_source.precedence, layer)),
+                Err(e) => {
+                    diag!(
+                        options,
+                        "WARNING: config database not loaded, falling back to files: {e:#}"
+                    );
+                    None
+                }
+            });
+    #[cfg(feature = "sqlite")]
+    if let Some((db::DbPrecedence::BelowFiles, _)) = db_layer {
+        let (_, layer) = db_layer.take().unwrap();
+        info.layers.insert(usize::from(base.is_some()), layer);
+    }
+    // For platforms that inject each secret as an env var of its own
+    let mut secret_vars = config::Map::new();
+    for (var, key) in options.secret_env_vars.iter().filter(|_| secrets_enabled) {
+        if let Some(value) = vars.get(var) {
+            let value = config::Value::new(None, value.to_owned());
+            tree::set_path(&mut secret_vars, &key.to_lowercase(), value);
+        }
+    }
+    if !secret_vars.is_empty() {
+        let source = tree::TreeSource(secret_vars);
+        info.layers
+            .push(Layer::new("secret env vars", LayerKind::Secret, source));
+    }
+
+    if let Some(ref var) = options.config_blob_var {
+        if let Some(blob) = vars.get(var) {
+            let text = base64::engine::general_purpose::STANDARD
+                .decode(blob.trim())
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .ok_or_else(|| anyhow!("{var} is not base64 encoded UTF-8 text"))?;
+            let document = yaml::Document {
+                path: var.clone(),
+                kind: LayerKind::File,
+                text,
+            };
+            let layers = yaml::layers(vec![document], false, &options.yaml_limits)?;
+            for layer in &layers {
+                layer
+                    .collect()
+                    .with_context(|| format!("{var} is not valid yaml"))?;
+            }
+            info.layers.extend(layers);
+        }
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some((db::DbPrecedence::AboveFiles, _)) = db_layer {
+        let (_, layer) = db_layer.take().unwrap();
+        info.layers.push(layer);
+    }
+
+    info.env_secret_keys = env_secret_keys(vars.secrets(), options);
+    let mut env_vars = vars.to_lowercase_map();
+    if let Some(ref var) = options.config_blob_var {
+        env_vars.remove(&var.to_lowercase());
+    }
+    // Only read as the keys they're declared for
+    for (var, _) in &options.secret_env_vars {
+        env_vars.remove(&var.to_lowercase());
+    }
+    let prefix = options.prefix.as_deref();
+    // Taken out up front, so that without a prefix of our own they aren't also read as unprefixed
+    let mut fallback_vars: Vec<(&str, config::Map<String, String>)> = options
+        .fallback_prefixes
+        .iter()
+        .map(|fallback| (fallback.as_str(), take_prefixed_vars(&mut env_vars, fallback)))
+        .collect();
+    let mut mapped_vars: Vec<(&str, &str, config::Map<String, String>)> = options
+        .env_prefix_map
+        .iter()
+        .map(|(env_prefix, key)| {
+            let vars = take_prefixed_vars(&mut env_vars, env_prefix);
+            (env_prefix.as_str(), key.as_str(), vars)
+        })
+        .collect();
+    if let Some(ref allowlist) = options.env_allowlist {
+        retain_allowed_vars(&mut env_vars, allowlist, prefix);
+        for (fallback, vars) in &mut fallback_vars {
+            retain_allowed_vars(vars, allowlist, Some(*fallback));
+        }
+        for (env_prefix, key, vars) in &mut mapped_vars {
+            retain_allowed_mapped_vars(vars, allowlist, *env_prefix, *key);
+        }
+    }
+    let json_layer = env_json_layer(&mut env_vars, options)?;
+    let mut fill_only_vars = take_fill_only_vars(&mut env_vars, options);
+    // We have to hardcode the list of config vars across the entire application
+    // that must be parsed as Vec<String> rather than String
+    let list_parse_keys: Vec<&String> = options
+        .list_parse_keys
+        .iter()
+        .chain(options.env_list_parse_keys.get(&env).into_iter().flatten())
+        .collect();
+    let lists = take_list_vars(&mut env_vars, &list_parse_keys, prefix);
+    let fill_only_lists = take_list_vars(&mut fill_only_vars, &list_parse_keys, prefix);
+    let env_source_from = |env_vars: config::Map<String, String>, prefix: Option<&str>| {
+        let mut env_source = if let Some(prefix) = prefix {
+            config::Environment::with_prefix(&prefix.to_lowercase()).prefix_separator("__")
+            // .convert_case(Case::Lower)
+        } else {
+            config::Environment::default()
+            // .convert_case(Case::Lower)
+        }
+        .separator("__")
+        .source(Some(env_vars));
+        if !list_parse_keys.is_empty() {
+            env_source = env_source.list_separator(",").try_parsing(true);
+            for key in &list_parse_keys {
+                env_source = env_source.with_list_parse_key(key.as_str());
+            }
+        }
+        env_source
+    };
+    let env_source = env_source_from(env_vars, prefix);
+    check_env_value_types(&env_source, prefix, options)?;
+    // Add in settings from the environment (with a prefix of <prefix>)
+    // Eg.. `AST__DEBUG=1 ./target/server` would set the `debug` key
+    let list_layer = (!lists.is_empty()).then(|| Layer::env(prefix, tree::TreeSource(lists)));
+    let env_source = tree::TreeSource(typed_env_values(&env_source)?);
+    // The fallback prefixes from lowest to highest precedence, then our own prefix on top
+    let mut env_layers = vec![];
+    for (fallback, mut vars) in fallback_vars.into_iter().rev() {
+        let lists = take_list_vars(&mut vars, &list_parse_keys, Some(fallback));
+        let fallback_source = env_source_from(vars, Some(fallback));
+        check_env_value_types(&fallback_source, Some(fallback), options)?;
+        let fallback_source = tree::TreeSource(typed_env_values(&fallback_source)?);
+        env_layers.push(Layer::env(Some(fallback), fallback_source));
+        if !lists.is_empty() {
+            env_layers.push(Layer::env(Some(fallback), tree::TreeSource(lists)));
+        }
+    }
+    for (env_prefix, key, vars) in mapped_vars {
+        env_layers.extend(mapped_env_layer(env_prefix, key, vars)?);
+    }
+    env_layers.push(Layer::env(prefix, env_source));
+    env_layers.extend(list_layer);
+    env_layers.extend(json_layer);
+    match options.env_precedence {
+        EnvPrecedence::Highest => {
+            info.layers.extend(env_layers);
+            if let Some(layer) = protect_secrets(&info.layers, options)? {
+                info.layers.push(layer);
+            }
+        }
+        // Secrets files already win over env vars at the bottom of the chain
+        EnvPrecedence::Lowest => {
+            for (i, layer) in env_layers.into_iter().enumerate() {
+                info.layers.insert(i, layer);
+            }
+        }
+    }
+    // Fill-only env vars only set what no file (or other source) sets. They aren't treated as env
+    // vars by protect_secrets(), since they can't shadow anything
+    if !fill_only_lists.is_empty() {
+        let source = tree::TreeSource(fill_only_lists);
+        info.layers
+            .insert(0, Layer::new("env (fill-only)", LayerKind::File, source));
+    }
+    if !fill_only_vars.is_empty() {
+        let source = tree::TreeSource(typed_env_values(&env_source_from(fill_only_vars, prefix))?);
+        info.layers
+            .insert(0, Layer::new("env (fill-only)", LayerKind::File, source));
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some((_, layer)) = db_layer {
+        info.layers.push(layer);
+    }
+    // A dev-only convenience for tweaking a value or two without editing the checked in files or
+    // unsetting env vars. This file shouldn't be checked in to git, and is only read from
+    // CONFIG_DIR in the dev environment
+    if env == Environment::Dev && !options.defaults_only {
+        let overrides = yaml::Document::read(
+            files,
+            format!("{config_dir}/local-overrides.yaml"),
+            LayerKind::File,
+        )?;
+        info.layers.extend(yaml::layers(
+            overrides.into_iter().collect(),
+            false,
+            &options.yaml_limits,
+        )?);
+    }
+
+    if let Some(ref verified_files) = verified_files {
+        verified_files.check()?;
+    }
+
+    let config_builder = builder_from(&info.layers);
+    #[cfg(feature = "secret-telemetry")]
+    {
+        info.secret_keys = access::secret_keys(&info.layers)?;
+    }
+    info.config_dir = files
+        .canonicalize(Path::new(&config_dir))
+        .unwrap_or_else(|_| config_dir.into());
+    Ok((config_builder, info))
+}
+
+fn yaml_documents(
+    dir: &str,
+    // `None` with defaults_only, which skips every file but default.yaml and its secrets file
+    env: Option<&Environment>,
+    profile_files: &[String],
+    conditional_files: &[&str],
+    // `None` with SecretsMode::Disabled, which skips every secrets file
+    secrets_keys: Option<&[String]>,
+    files: &dyn FileProvider,
+    info: &mut LoadInfo,
+) -> Result<Vec<yaml::Document>> {
+    let read = |name: &str, kind| yaml::Document::read(files, format!("{dir}/{name}"), kind);
+    let read_secrets = |name: &str| match secrets_keys {
+        Some(_) => read(name, LayerKind::Secret),
+        None => Ok(None),
+    };
+    let mut decrypt = |name: &str| -> Result<Option<yaml::Document>> {
+        let Some(secrets_keys) = secrets_keys else {
+            return Ok(None);
+        };
+        let path = format!("{dir}/{name}");
+        let Some(decrypted) = info.decrypt(path.clone(), secrets_keys, files) else {
+            return Ok(None);
+        };
+        Ok(Some(yaml::Document {
+            path,
+            kind: LayerKind::Secret,
+            text: files::text(&path, decrypted)?,
+        }))
+    };
+
+    let mut documents = vec![];
+    // Start off by merging in the "default" configuration file
+    documents.extend(read("default.yaml", LayerKind::File)?);
+    documents.extend(decrypt("default-secrets.yaml.enc")?);
+    if let Some(env) = env {
+        // Add in the current environment file
+        documents.extend(match read(&format!("{env}.yaml"), LayerKind::File)? {
+            Some(document) => Some(document),
+            None => read(&format!("{env}.yml"), LayerKind::File)?,
+        });
+        // Add in the secrets file for the current environment, which might be used as plaintext
+        // during local development. This file shouldn't be checked in to git
+        documents.extend(read_secrets(&format!("{env}-secrets.yaml"))?);
+        documents.extend(decrypt(&format!("{env}-secrets.yaml.enc"))?);
+        documents.extend(decrypt("local-secrets.yaml.enc")?);
+
+        for name in profile_files {
+            documents.extend(read(name, LayerKind::File)?);
+        }
+        for name in conditional_files {
+            documents.extend(read(name, LayerKind::File)?);
+        }
+
+        // Add in a local configuration file
+        // This file shouldn't be checked in to git
+        // Note that this file is _optional_
+        documents.extend(read("local.yaml", LayerKind::File)?);
+    }
+
+    for document in &mut documents {
+        document.text = secrets::decrypt_inline(
+            &document.path,
+            &document.text,
+            secrets_keys.unwrap_or_default(),
+        )?;
+    }
+    Ok(documents)
+}
+
+// Env vars come last, so they would otherwise also override values from secrets files
+fn protect_secrets(layers: &[Layer], options: &InitOptions) -> Result<Option<Layer>> {
+    let mut secret_values = BTreeMap::new();
+    let mut env_values = BTreeMap::new();
+    for layer in layers {
+        match layer.kind {
+            LayerKind::Secret => secret_values.extend(layer.values()?),
+            LayerKind::Env => env_values.extend(layer.values()?),
+            LayerKind::File => {}
+        }
+    }
+
+    let mut restored = config::Map::new();
+    for key in env_values.keys() {
+        let Some(secret) = secret_values.get(key) else {
+            continue;
+        };
+        if options.secrets_authoritative_keys.contains(key) {
+            diag!(
+                options,
+                "Ignoring env var for {key}, which can only be set from secrets files"
+            );
+            tree::set_path(&mut restored, key, secret.clone());
+        } else {
+            diag!(
+                options,
+                "WARNING: an env var overrides the value of {key} from secrets files"
+            );
+        }
+    }
+
+    if restored.is_empty() {
+        return Ok(None);
+    }
+    let source = tree::TreeSource(restored);
+    Ok(Some(Layer::new("secrets", LayerKind::Secret, source)))
+}
+
+fn scope_to_namespace(config: config::Config, namespace: &str) -> Result<config::Config> {
+    let mut table = config.collect()?;
+    let scoped = match tree::remove_path(&mut table, namespace) {
+        Some(value) => match value.kind {
+            config::ValueKind::Table(scoped) => scoped,
+            _ => bail!("Config key {namespace} for the app namespace is not a table"),
+        },
+        None => config::Map::new(),
+    };
+    tree::rebuild(scoped)
+}
+
+fn check_secret_only_keys(
+    config: &config::Config,
+    layers: &[Layer],
+    secret_paths: &BTreeSet<String>,
+    options: &InitOptions,
+) -> Result<()> {
+    if options.secret_only_keys.is_empty() {
+        return Ok(());
+    }
+
+    let plaintext_files: Vec<&str> = layers
+        .iter()
+        .filter(|layer| {
+            layer.kind == LayerKind::File
+                && (layer.name.ends_with(".yaml") || layer.name.ends_with(".yml"))
+        })
+        .map(|layer| layer.name.as_str())
+        .collect();
+    let mut violations = vec![];
+    for (key, value) in tree::flatten(&config.collect()?) {
+        let Some(origin) = value
+            .origin()
+            .filter(|origin| plaintext_files.contains(origin))
+        else {
+            continue;
+        };
+        // `!secret` values and Vault references are secrets, even though they're in plaintext
+        // files
+        if is_secret_path(secret_paths, &key) {
+            continue;
+        }
+        #[cfg(feature = "vault")]
+        if vault::is_reference(&value) {
+            continue;
+        }
+        if options
+            .secret_only_keys
+            .iter()
+            .any(|pattern| matches_pattern(pattern, &key))
+        {
+            violations.push(format!("{key} (set in {origin})"));
+        }
+    }
+
+    if !violations.is_empty() {
+        bail!(
+            "Secret-only keys are set in plaintext yaml files:\n  {}",
+            violations.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+// Walks the layers from lowest to highest precedence, keeping track of the plaintext yaml file that
+// last set each key
+fn check_secret_overrides(layers: &[Layer], options: &InitOptions) -> Result<()> {
+    let mut plaintext: BTreeMap<String, &str> = BTreeMap::new();
+    let mut overrides = vec![];
+    for layer in layers {
+        let is_yaml = layer.name.ends_with(".yaml") || layer.name.ends_with(".yml");
+        match layer.kind {
+            LayerKind::File if is_yaml => {
+                for key in layer.values()?.into_keys() {
+                    plaintext.insert(key, &layer.name);
+                }
+            }
+            LayerKind::Secret => {
+                for key in layer.values()?.into_keys() {
+                    if let Some(file) = plaintext.remove(&key) {
+                        let secret = &layer.name;
+                        overrides.push(format!("{key} (set in {file}, overridden by {secret})"));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if overrides.is_empty() {
+        return Ok(());
+    }
+    let overrides = overrides.join("\n  ");
+    match options.secret_overrides {
+        SecretOverrides::Warn => diag!(
+            options,
+            "WARNING: values from secrets override plaintext config:\n  {overrides}"
+        ),
+        SecretOverrides::Error => {
+            bail!("Values from secrets override plaintext config:\n  {overrides}")
+        }
+    }
+    Ok(())
+}
+
+fn check_placeholders(config: &config::Config, options: &InitOptions) -> Result<()> {
+    if !options.reject_placeholders {
+        return Ok(());
+    }
+
+    let placeholder = options.placeholder.as_deref().unwrap_or(DEFAULT_PLACEHOLDER);
+    let mut keys = vec![];
+    tree::visit_leaves_mut(&mut config.collect()?, &mut |key, value| {
+        if matches!(&value.kind, config::ValueKind::String(text) if text == placeholder) {
+            keys.push(key.to_owned());
+        }
+        Ok(())
+    })?;
+
+    if !keys.is_empty() {
+        keys.sort();
+        bail!(
+            "Config keys are still set to the placeholder {placeholder}:\n  {}",
+            keys.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+fn check_allowed_sections(config: &config::Config, options: &InitOptions) -> Result<()> {
+    let Some(ref allowed) = options.allowed_sections else {
+        return Ok(());
+    };
+
+    let mut unknown: Vec<String> = config
+        .collect()?
+        .into_keys()
+        .filter(|section| !allowed.contains(section))
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        bail!(
+            "Unknown top-level config sections: {} (allowed are {})",
+            unknown.join(", "),
+            allowed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+// Whether `key` matches `pattern`, where `*` matches any run of characters
+fn matches_pattern(pattern: &str, key: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = key.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        let Some(pos) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[pos + part.len()..];
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+// Recomputes the array_merge keys from the values each layer sets for them
+fn merge_arrays(
+    config: config::Config,
+    layers: &[Layer],
+    options: &InitOptions,
+) -> Result<config::Config> {
+    if options
+        .array_merge
+        .iter()
+        .all(|(_, strategy)| *strategy == MergeStrategy::Replace)
+    {
+        return Ok(config);
+    }
+
+    let layer_values = layers
+        .iter()
+        .map(Layer::values)
+        .collect::<Result<Vec<_>>>()?;
+    let mut table = config.collect()?;
+    for (key, strategy) in &options.array_merge {
+        if *strategy == MergeStrategy::Replace {
+            continue;
+        }
+        let mut merged: Option<Vec<config::Value>> = None;
+        for values in &layer_values {
+            let Some(value) = values.get(key) else {
+                continue;
+            };
+            let config::ValueKind::Array(array) = &value.kind else {
+                merged = None;
+                continue;
+            };
+            let merged = merged.get_or_insert_with(Vec::new);
+            for value in array {
+                if *strategy == MergeStrategy::Unique
+                    && merged.iter().any(|existing| existing.kind == value.kind)
+                {
+                    continue;
+                }
+                merged.push(value.clone());
+            }
+        }
+        if let Some(merged) = merged {
+            tree::set_path(
+                &mut table,
+                key,
+                config::Value::new(None, config::ValueKind::Array(merged)),
+            );
+        }
+    }
+    tree::rebuild(table)
+}
+
+// A value under the new key wins over one still set under the old key
+fn apply_key_renames(config: config::Config, options: &InitOptions) -> Result<config::Config> {
+    let key_renames = &options.key_renames;
+    if key_renames.is_empty() {
+        return Ok(config);
+    }
+
+    let mut table = config.collect()?;
+    for (old, new) in key_renames {
+        let Some(value) = tree::remove_path(&mut table, old) else {
+            continue;
+        };
+        if tree::get_path(&table, new).is_some() {
+            diag!(
+                options,
+                "Config key {old} is deprecated and ignored since {new} is also set"
+            );
+        } else {
+            diag!(options, "Config key {old} is deprecated, use {new} instead");
+            tree::set_path(&mut table, new, value);
+        }
+    }
+    tree::rebuild(table)
+}
+
+// Drops every env var that isn't for a key in `allowlist` or under one
+fn retain_allowed_vars(
+    env_vars: &mut config::Map<String, String>,
+    allowlist: &[String],
+    prefix: Option<&str>,
+) {
+    let allowed: Vec<String> = allowlist
+        .iter()
+        .map(|key| env_var_name(key, prefix).to_lowercase())
+        .collect();
+    env_vars.retain(|name, _| {
+        allowed
+            .iter()
+            .any(|var| name == var || name.starts_with(&format!("{var}__")))
+    });
+}
+
+// Like retain_allowed_vars(), for the vars of an env_prefix_map prefix that maps to `key`
+fn retain_allowed_mapped_vars(
+    env_vars: &mut config::Map<String, String>,
+    allowlist: &[String],
+    env_prefix: &str,
+    key: &str,
+) {
+    let start = format!("{}__", env_prefix.to_lowercase());
+    let key = key.to_lowercase();
+    env_vars.retain(|name, _| {
+        let rest = name.strip_prefix(&start).unwrap_or(name).replace("__", ".");
+        let var_key = format!("{key}.{rest}");
+        allowlist.iter().any(|allowed| {
+            let allowed = allowed.to_lowercase();
+            var_key == allowed || var_key.starts_with(&format!("{allowed}."))
+        })
+    });
+}
+
+// The vars of an env_prefix_map prefix, as a layer setting the sub-tree at `key`
+fn mapped_env_layer(
+    env_prefix: &str,
+    key: &str,
+    env_vars: config::Map<String, String>,
+) -> Result<Option<Layer>> {
+    if env_vars.is_empty() {
+        return Ok(None);
+    }
+    let env_source = config::Environment::with_prefix(&env_prefix.to_lowercase())
+        .prefix_separator("__")
+        .separator("__")
+        .source(Some(env_vars));
+    // The env source has flat, dotted keys (eg. `tls.cert`), which are set one by one so nested
+    // vars end up in nested tables
+    let key = key.to_lowercase();
+    let mut table = config::Map::new();
+    for (path, value) in typed_env_values(&env_source)? {
+        tree::set_path(&mut table, &format!("{key}.{path}"), value);
+    }
+    Ok(Some(Layer::env(Some(env_prefix), tree::TreeSource(table))))
+}
+
+// The config keys that the vars from encrypted .env files set, going by their names the way the env
+// layers read them
+fn env_secret_keys(names: &BTreeSet<String>, options: &InitOptions) -> Vec<String> {
+    let mut keys = vec![];
+    for name in names {
+        let name = name.to_lowercase();
+        let mapped = options.env_prefix_map.iter().find_map(|(env_prefix, key)| {
+            let rest = name.strip_prefix(&format!("{}__", env_prefix.to_lowercase()))?;
+            Some(format!("{}.{rest}", key.to_lowercase()))
+        });
+        let prefixed = || {
+            let mut prefixes = options.prefix.iter().chain(&options.fallback_prefixes);
+            prefixes.find_map(|prefix| {
+                let start = format!("{}__", prefix.to_lowercase());
+                name.strip_prefix(&start).map(str::to_owned)
+            })
+        };
+        let key = match mapped.or_else(prefixed) {
+            Some(key) => key,
+            None if options.prefix.is_none() => name,
+            None => continue,
+        };
+        keys.push(key.replace("__", "."));
+    }
+    keys
+}
+
+// Takes the env vars with `prefix` (in any case) out of the regular env vars
+fn take_prefixed_vars(
+    env_vars: &mut config::Map<String, String>,
+    prefix: &str,
+) -> config::Map<String, String> {
+    let start = format!("{}__", prefix.to_lowercase());
+    let matching: Vec<String> = env_vars
+        .keys()
+        .filter(|name| name.starts_with(&start))
+        .cloned()
+        .collect();
+    let mut prefixed_vars = config::Map::new();
+    for name in matching {
+        if let Some(value) = env_vars.remove(&name) {
+            prefixed_vars.insert(name, value);
+        }
+    }
+    prefixed_vars
+}
+
+// Takes the env vars for env_fill_only_keys (and any keys under them) out of the regular env vars
+fn take_fill_only_vars(
+    env_vars: &mut config::Map<String, String>,
+    options: &InitOptions,
+) -> config::Map<String, String> {
+    let mut fill_only_vars = config::Map::new();
+    for key in &options.env_fill_only_keys {
+        let var = env_var_name(key, options.prefix.as_deref()).to_lowercase();
+        let nested = format!("{var}__");
+        let matching: Vec<String> = env_vars
+            .keys()
+            .filter(|name| **name == var || name.starts_with(&nested))
+            .cloned()
+            .collect();
+        for name in matching {
+            if let Some(value) = env_vars.remove(&name) {
+                fill_only_vars.insert(name, value);
+            }
+        }
+    }
+    fill_only_vars
+}
+
+// The env vars for env_json_keys are taken out of the regular env vars, since their values are
+// JSON objects rather than scalars
+fn env_json_layer(
+    env_vars: &mut config::Map<String, String>,
+    options: &InitOptions,
+) -> Result<Option<Layer>> {
+    let mut table = config::Map::new();
+    for key in &options.env_json_keys {
+        let var = env_var_name(key, options.prefix.as_deref());
+        let Some(json) = env_vars.remove(&var.to_lowercase()) else {
+            continue;
+        };
+        let section = config::File::from_str(&json, FileFormat::Json)
+            .collect()
+            .with_context(|| format!("{var} is not a JSON object"))?;
+        let section = config::Value::new(None, config::ValueKind::Table(section));
+        tree::set_path(&mut table, &key.to_lowercase(), section);
+    }
+
+    if table.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Layer::env(
+        options.prefix.as_deref(),
+        tree::TreeSource(table),
+    )))
+}
+
+// The env vars for list parse keys are taken out of the regular env vars and split here, rather
+// than by the env source, so that an element can contain the separator escaped with a backslash,
+// eg. `TAGS=a,b\,c` for `["a", "b,c"]`. Elements are parsed as bools and numbers where possible
+fn take_list_vars(
+    env_vars: &mut config::Map<String, String>,
+    list_parse_keys: &[&String],
+    prefix: Option<&str>,
+) -> config::Map<String, config::Value> {
+    let mut table = config::Map::new();
+    for key in list_parse_keys {
+        let var = env_var_name(key, prefix).to_lowercase();
+        let Some(list) = env_vars.remove(&var) else {
+            continue;
+        };
+        let elements = split_list(&list)
+            .iter()
+            .map(|element| config::Value::new(None, args::coerce(element)))
+            .collect();
+        let value = config::Value::new(None, config::ValueKind::Array(elements));
+        tree::set_path(&mut table, &key.to_lowercase(), value);
+    }
+    table
+}
+
+// Splits on commas, except for `\,`, with `\\` for a literal backslash
+fn split_list(list: &str) -> Vec<String> {
+    if list.is_empty() {
+        return vec![];
+    }
+    let mut elements = vec![String::new()];
+    let mut chars = list.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next @ (',' | '\\')) => elements.last_mut().unwrap().push(next),
+                Some(next) => {
+                    let element = elements.last_mut().unwrap();
+                    element.push('\\');
+                    element.push(next);
+                }
+                None => elements.last_mut().unwrap().push('\\'),
+            },
+            ',' => elements.push(String::new()),
+            c => elements.last_mut().unwrap().push(c),
+        }
+    }
+    elements
+}
+
+// The reverse of split_list()
+fn join_list(elements: impl Iterator<Item = String>) -> String {
+    elements
+        .map(|element| element.replace('\\', "\\\\").replace(',', "\\,"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Env var values are strings, which config only converts to the type a field asks for. Fields that
+// serde buffers before deserializing them (eg. into a `#[serde(flatten)]` map) never ask, so they
+// would get a string where a file gives a bool or number. Values that parse as a bool or number
+// are converted up front instead, like yaml does, as long as they read back exactly the same, so
+// eg. `0123` stays a string
+fn typed_env_values(
+    env_source: &config::Environment,
+) -> Result<config::Map<String, config::Value>> {
+    let mut values = env_source.collect()?;
+    tree::visit_leaves_mut(&mut values, &mut |_, value| {
+        if let config::ValueKind::String(s) = &value.kind {
+            value.kind = args::coerce(s);
+        }
+        Ok(())
+    })?;
+    Ok(values)
+}
+
+fn check_env_value_types(
+    env_source: &config::Environment,
+    prefix: Option<&str>,
+    options: &InitOptions,
+) -> Result<()> {
+    if options.env_value_types.is_empty() {
+        return Ok(());
+    }
+
+    let values = env_source.collect()?;
+    let mut invalid = vec![];
+    for (key, expected) in &options.env_value_types {
+        let Some(value) = values.get(&key.to_lowercase()) else {
+            continue;
+        };
+        let valid = match expected {
+            ValueType::Bool => value.clone().into_bool().is_ok(),
+            ValueType::Int => value.clone().into_int().is_ok(),
+            ValueType::Float => value.clone().into_float().is_ok(),
+            ValueType::String => value.clone().into_string().is_ok(),
+        };
+        if !valid {
+            let var = env_var_name(key, prefix);
+            invalid.push(format!("{var}={value} (expected {expected})"));
+        }
+    }
+
+    if !invalid.is_empty() {
+        invalid.sort();
+        bail!("Invalid values for env vars:\n  {}", invalid.join("\n  "));
+    }
+    Ok(())
+}
+
+// The env var that sets `key`, eg. `AST__SERVER__PORT` for `server.port` with prefix `AST`
+fn env_var_name(key: &str, prefix: Option<&str>) -> String {
+    let var = key.replace('.', "__").to_uppercase();
+    match prefix {
+        Some(prefix) => format!("{prefix}__{var}"),
+        None => var,
+    }
+}
+
+// A missing env file is fine, but one that is present and fails to parse (eg. a line without `=`)
+// would otherwise silently leave the variables it was meant to set unset. Without `strict`, the
+// vars before the line that failed to parse are still used
+fn read_env_file(
+    path: &str,
+    files: &dyn FileProvider,
+    options: &InitOptions,
+) -> Result<Vec<(String, String)>> {
+    let failed = |e: dotenvy::Error| {
+        if options.strict_env_files {
+            return Err(anyhow!("Failed to parse {path}: {e}"));
+        }
+        diag!(
+            options,
+            "Failed to parse {path}, some of its env vars may not be set: {e}"
+        );
+        Ok(())
+    };
+
+    let contents = match files.read(Path::new(path)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => {
+            failed(dotenvy::Error::Io(e))?;
+            return Ok(vec![]);
+        }
+    };
+    let mut vars = vec![];
+    // Unlike a line that fails to parse, contents that aren't UTF-8 are always an error
+    let text = files::text(path, contents)?;
+    for item in dotenvy::from_read_iter(text.as_bytes()) {
+        match item {
+            Ok(var) => vars.push(var),
+            Err(e) => {
+                failed(e)?;
+                break;
+            }
+        }
+    }
+    Ok(vars)
+}
+
+// Every file the loader may read from the config dir, whether plaintext or encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpectedFileKind {
+    Yaml,
+    Env,
+    // Plaintext secrets files
+    Secret,
+    // Encrypted secrets files, whether yaml or .env
+    Encrypted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedPath {
+    pub path: PathBuf,
+    pub kind: ExpectedFileKind,
+    // 0 for the highest precedence file. Alternatives such as `<env>.yaml` and `<env>.yml` share
+    // an index
+    pub precedence: usize,
+}
+
+// Every path the loader would read from `config_dir` for `env`, from highest to lowest
+// precedence, eg. for editor integrations. Nothing is read: the files may or may not exist. Uses
+// the env file suffix given at init, if any, and leaves out base_dir, profile and conditional files
+pub fn expected_paths(config_dir: &Path, env: &Environment) -> Vec<ExpectedPath> {
+    use ExpectedFileKind::*;
+
+    let suffix = INIT
+        .get()
+        .map_or(".env", |init| init.options.env_file_suffix());
+    let overrides = match env {
+        Environment::Dev => vec![("local-overrides.yaml".into(), Yaml)],
+        _ => vec![],
+    };
+    let levels: Vec<Vec<(String, ExpectedFileKind)>> = vec![
+        overrides,
+        vec![(suffix.into(), Env)],
+        vec![(format!("local-secrets{suffix}.enc"), Encrypted)],
+        vec![(format!("local{suffix}"), Env)],
+        vec![(format!("{env}-secrets{suffix}.enc"), Encrypted)],
+        vec![(format!("{env}{suffix}"), Env)],
+        vec![(format!("default-secrets{suffix}.enc"), Encrypted)],
+        vec![(format!("default{suffix}"), Env)],
+        vec![("local.yaml".into(), Yaml)],
+        vec![("local-secrets.yaml.enc".into(), Encrypted)],
+        vec![(format!("{env}-secrets.yaml.enc"), Encrypted)],
+        vec![(format!("{env}-secrets.yaml"), Secret)],
+        vec![(format!("{env}.yaml"), Yaml), (format!("{env}.yml"), Yaml)],
+        vec![("default-secrets.yaml.enc".into(), Encrypted)],
+        vec![("default.yaml".into(), Yaml)],
+    ];
+    levels
+        .into_iter()
+        .enumerate()
+        .flat_map(|(precedence, names)| {
+            names.into_iter().map(move |(name, kind)| ExpectedPath {
+                path: config_dir.join(name),
+                kind,
+                precedence,
+            })
+        })
+        .collect()
+}
+
+fn config_file_names(env: &Environment, env_file_suffix: &str) -> Vec<String> {
+    let mut names = vec![
+        env_file_suffix.into(),
+        format!("local{env_file_suffix}"),
+        format!("{env}{env_file_suffix}"),
+        format!("default{env_file_suffix}"),
+        format!("local-secrets{env_file_suffix}.enc"),
+        format!("{env}-secrets{env_file_suffix}.enc"),
+        format!("default-secrets{env_file_suffix}.enc"),
+        "default.yaml".into(),
+        "default-secrets.yaml.enc".into(),
+        format!("{env}.yaml"),
+        format!("{env}.yml"),
+        format!("{env}-secrets.yaml"),
+        format!("{env}-secrets.yaml.enc"),
+        "local-secrets.yaml.enc".into(),
+        "local.yaml".into(),
+    ];
+    if *env == Environment::Dev {
+        names.push("local-overrides.yaml".into());
+    }
+    names
+}
+
+// Loads `T` for `env` from `config_dir` into a config of its own, leaving the global config and
+// the process env untouched. Uses the options given at init, if any. Errors can be turned into a
+// ValidationError for a machine-readable report
+pub fn validate<T: DeserializeOwned>(config_dir: impl AsRef<Path>, env: Environment) -> Result<T> {
+    let default_init;
+    let init = match INIT.get() {
+        Some(init) => init,
+        None => {
+            default_init = Init {
+                options: InitOptions::default(),
+                customize: Box::new(|config_builder| config_builder),
+                base: None,
+                build_script: false,
+            };
+            &default_init
+        }
+    };
+    let config_dir = config_dir.as_ref().to_string_lossy().into_owned();
+    let loaded = load(init, config_dir, env, Vars::from_process_env(false), vec![])?;
+    deserialize_at(loaded.config, None)
+}
+
+// For build scripts: checks that `T` deserializes from the files in `config_dir` for `env`, eg. to
+// fail the build of a service with checked in config when its prod.yaml is invalid. Uses the
+// default options, reads no env vars and loads no secrets (secrets files and `!secret` values are
+// skipped, and `vault:` values aren't resolved), and has cargo rerun the build script when the
+// config dir changes
+pub fn validate_at_build<T: DeserializeOwned>(
+    config_dir: impl AsRef<Path>,
+    env: Environment,
+) -> Result<()> {
+    let init = Init {
+        options: InitOptions {
+            quiet: true,
+            secrets_mode: SecretsMode::Disabled,
+            ..Default::default()
+        },
+        customize: Box::new(|config_builder| config_builder),
+        base: None,
+        build_script: true,
+    };
+    let config_dir = config_dir.as_ref().to_string_lossy().into_owned();
+    println!("cargo:rerun-if-changed={config_dir}");
+    let loaded = load(&init, config_dir.clone(), env, Vars::empty(), vec![])?;
+    deserialize_at::<T, _>(loaded.config, None)
+        .with_context(|| format!("Invalid {env} config in {config_dir}"))?;
+    Ok(())
+}
+
+// Validates every environment, eg. to check in CI that none of them fails to deserialize
+pub fn load_all_environments<T: DeserializeOwned>(
+    config_dir: impl AsRef<Path>,
+) -> Vec<(Environment, Result<T>)> {
+    Environment::iter()
+        .map(|env| (env, validate(config_dir.as_ref(), env)))
+        .collect()
+}
+
+pub trait LoadConfig: DeserializeOwned {
+    fn load() -> Self {
+        deserialize(&current().unwrap()).unwrap()
+    }
+
+    // Like load(), along with the environment the config was loaded for. Both come from the same
+    // load, so they can't disagree if a reload() happens in between
+    fn load_with_env() -> (Environment, Self) {
+        let loaded = current().unwrap();
+        (loaded.env, deserialize(&loaded).unwrap())
+    }
+}
+
+// Like LoadConfig::load(), but returns an error rather than panicking when the config isn't
+// initialized or doesn't deserialize into `T`
+pub fn safe_load<T: LoadConfig>() -> Result<T> {
+    let loaded = current()?;
+    deserialize(&loaded).with_context(|| {
+        format!(
+            "Failed to deserialize the config into {}",
+            any::type_name::<T>()
+        )
+    })
+}
+
+fn deserialize<T: DeserializeOwned>(loaded: &Loaded) -> Result<T> {
+    deserialize_config(loaded.config.clone(), &loaded.info)
+}
+
+fn deserialize_config<T: DeserializeOwned>(
+    config: config::Config,
+    #[cfg_attr(not(feature = "secret-telemetry"), allow(unused_variables))] info: &LoadInfo,
+) -> Result<T> {
+    #[cfg(feature = "secret-telemetry")]
+    let value = access::deserialize(config, &info.secret_keys)?;
+    #[cfg(not(feature = "secret-telemetry"))]
+    let value = deserialize_at(config, None)?;
+    Ok(value)
+}
+
+// Like safe_load(), along with the values of every key `T` has no field for, by dotted key (eg.
+// `server.legacy_port`), for logging or forwarding them rather than silently dropping them. Keys
+// inside arrays of tables aren't included
+pub fn load_with_extras<T: DeserializeOwned>() -> Result<(T, BTreeMap<String, config::Value>)> {
+    let loaded = current()?;
+    let mut ignored = vec![];
+    let mut callback = |path: serde_ignored::Path<'_>| ignored.push(path.to_string());
+    let deserializer = serde_ignored::Deserializer::new(loaded.config.clone(), &mut callback);
+    let value = deserialize_at(deserializer, None)?;
+    let table = loaded.config.collect()?;
+    let extras = ignored
+        .into_iter()
+        .filter_map(|key| {
+            let value = tree::get_path(&table, &key)?.clone();
+            Some((key, value))
+        })
+        .collect();
+    Ok((value, extras))
+}
+
+// Like LoadConfig::load(), but with `T::default()` as the lowest precedence source, so that fields
+// no source sets keep their default values rather than failing to deserialize
+pub fn load_with_default<T: LoadConfig + Default + serde::Serialize>() -> T {
+    let loaded = current().unwrap();
+    let config = config::Config::builder()
+        .add_source(config::Config::try_from(&T::default()).unwrap())
+        .add_source(loaded.config.clone())
+        .build()
+        .unwrap();
+    deserialize_config(config, &loaded.info).unwrap()
+}
+
+// Deserializes `T` with the dotted path of the field that failed (eg. `http.tls.cert_path`) in the
+// error, which config's own errors don't always give for nested structs. `section` is the key of
+// the value being deserialized, when it isn't the whole config
+pub(crate) fn deserialize_at<'de, T, D>(deserializer: D, section: Option<&str>) -> Result<T>
+where
+    T: serde::Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let path = match (section, e.path().to_string()) {
+            (Some(section), path) if path == "." => section.to_owned(),
+            (Some(section), path) => format!("{section}.{path}"),
+            (None, path) => path,
+        };
+        anyhow::Error::new(e.into_inner()).context(validation::InvalidValueAt(path))
+    })
+}
+
+// Deserializes `T` once and hands out clones of the same Arc from then on, for hot paths where the
+// clone and deserialize done by every `load()` call adds up. The cached values are dropped
+// whenever a new config is swapped in, eg. by reload() or apply_overlay()
+pub fn arc_load<T: LoadConfig + Send + Sync + 'static>() -> Arc<T> {
+    let loaded = current().unwrap();
+    if let Some(cached) = loaded.cache.read().unwrap().get(&TypeId::of::<T>()) {
+        return cached.clone().downcast().unwrap();
+    }
+    // Deserialized without holding the lock, so a slow deserialize doesn't block other types.
+    // Should two threads race, the first value stored wins
+    let value: Arc<dyn Any + Send + Sync> = Arc::new(deserialize::<T>(&loaded).unwrap());
+    let mut cache = loaded.cache.write().unwrap();
+    cache
+        .entry(TypeId::of::<T>())
+        .or_insert(value)
+        .clone()
+        .downcast()
+        .unwrap()
+}
+
+// A section of the config (eg. `database`) that is only deserialized on first access, so a large
+// config doesn't have to be deserialized up front. Meant for statics, eg.
+// `static DATABASE: LazyConfig<Database> = LazyConfig::new("database");`. The section is
+// deserialized once, and isn't updated by later reloads
+#[derive(Debug)]
+pub struct LazyConfig<T> {
+    key: &'static str,
+    value: OnceLock<T>,
+}
+
+impl<T: DeserializeOwned> LazyConfig<T> {
+    pub const fn new(key: &'static str) -> Self {
+        Self {
+            key,
+            value: OnceLock::new(),
+        }
+    }
+
+    pub fn get(&self) -> &T {
+        self.value.get_or_init(|| {
+            let loaded = current().unwrap();
+            let section: config::Value = loaded
+                .config
+                .get(self.key)
+                .with_context(|| format!("Config section {} not found", self.key))
+                .unwrap();
+            deserialize_at(section, Some(self.key)).unwrap()
+        })
+    }
+}
+
+impl<T: DeserializeOwned> std::ops::Deref for LazyConfig<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.get()
+    }
+}
+
+// Writes `value` for `key` (eg. `server.port`) to local.yaml, keeping the rest of the file and its
+// comments as they are, then reloads so the change takes effect
+pub fn set_persisted(key: &str, value: impl Into<config::Value>) -> Result<()> {
+    INIT.get().ok_or_else(not_initialized)?;
+    let path = current()?.info.config_dir.join("local.yaml");
+    persist::set_yaml_value(&path, key, &value.into())?;
+    reload()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn yaml_layer(name: &str, kind: LayerKind, text: &str) -> Layer {
+        let document = yaml::Document {
+            path: name.into(),
+            kind,
+            text: text.into(),
+        };
+        yaml::layers(vec![document], false, &YamlLimits::default())
+            .unwrap()
+            .remove(0)
+    }
+
+    // The merged config of `layers` as a redacted yaml snapshot
+    fn redacted_snapshot(
+        options: InitOptions,
+        layers: Vec<Layer>,
+        env_secret_keys: &[&str],
+    ) -> String {
+        let init = Init {
+            options,
+            customize: Box::new(|config_builder| config_builder),
+            base: None,
+            build_script: false,
+        };
+        let info = LoadInfo {
+            layers,
+            env_secret_keys: strings(env_secret_keys),
+            ..Default::default()
+        };
+        let loaded = finish(&init, builder_from(&info.layers), Environment::Dev, info).unwrap();
+        render_snapshot(&loaded, FileFormat::Yaml, true).unwrap()
+    }
+
+    #[test]
+    fn redacts_values_from_secrets_files() {
+        let default = "db:\n  host: db.internal\n  password: x\n";
+        let layers = vec![
+            yaml_layer("default.yaml", LayerKind::File, default),
+            yaml_layer("prod-secrets.yaml", LayerKind::Secret, "db:\n  password: hunter2\n"),
+        ];
+        let snapshot = redacted_snapshot(InitOptions::default(), layers, &[]);
+        assert!(!snapshot.contains("hunter2"));
+        assert!(snapshot.contains("db.internal"));
+    }
+
+    #[test]
+    fn redacts_inline_secrets() {
+        let password = secrets::quote(&format!("{}hunter2", secrets::DECRYPTED));
+        let text =
+            format!("db:\n  host: db.internal\n  password: {password}\ntokens:\n  - {password}\n");
+        let layers = vec![yaml_layer("prod.yaml", LayerKind::File, &text)];
+        let snapshot = redacted_snapshot(InitOptions::default(), layers, &[]);
+        assert!(!snapshot.contains("hunter2"));
+        assert!(!snapshot.contains(secrets::DECRYPTED));
+        assert!(snapshot.contains("db.internal"));
+    }
+
+    fn secret_only_password() -> InitOptions {
+        InitOptions {
+            secret_only_keys: strings(&["*.password"]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn allows_inline_secrets_for_secret_only_keys() {
+        let password = secrets::quote(&format!("{}hunter2", secrets::DECRYPTED));
+        let text = format!("db:\n  password: {password}\n");
+        let layers = vec![yaml_layer("prod.yaml", LayerKind::File, &text)];
+        let config = builder_from(&layers).build().unwrap();
+        let (config, secret_paths) = secrets::take_decrypted(config).unwrap();
+        let options = secret_only_password();
+        assert!(check_secret_only_keys(&config, &layers, &secret_paths, &options).is_ok());
+
+        let layers = vec![yaml_layer("prod.yaml", LayerKind::File, "db:\n  password: x\n")];
+        let config = builder_from(&layers).build().unwrap();
+        let plaintext = check_secret_only_keys(&config, &layers, &BTreeSet::new(), &options);
+        assert!(plaintext.is_err());
+    }
+
+    #[cfg(feature = "vault")]
+    #[test]
+    fn allows_vault_references_for_secret_only_keys() {
+        let text = "db:\n  password: vault:secret/data/app#password\n";
+        let layers = vec![yaml_layer("prod.yaml", LayerKind::File, text)];
+        let config = builder_from(&layers).build().unwrap();
+        let options = secret_only_password();
+        assert!(check_secret_only_keys(&config, &layers, &BTreeSet::new(), &options).is_ok());
+    }
+
+    #[test]
+    fn redacts_values_from_encrypted_env_files() {
+        let options = InitOptions {
+            prefix: Some("APP".into()),
+            ..Default::default()
+        };
+        let names = BTreeSet::from(["APP__DB__PASSWORD".to_owned()]);
+        let keys = env_secret_keys(&names, &options);
+        assert_eq!(keys, ["db.password"]);
+
+        let mut table = config::Map::new();
+        tree::set_path(&mut table, "db.password", config::Value::new(None, "hunter2"));
+        tree::set_path(&mut table, "db.host", config::Value::new(None, "db.internal"));
+        let layers = vec![Layer::env(Some("APP"), tree::TreeSource(table))];
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let snapshot = redacted_snapshot(options, layers, &keys);
+        assert!(!snapshot.contains("hunter2"));
+        assert!(snapshot.contains("db.internal"));
+    }
+
+    #[test]
+    fn maps_encrypted_env_vars_to_keys() {
+        let options = InitOptions {
+            prefix: Some("APP".into()),
+            fallback_prefixes: strings(&["OLD"]),
+            env_prefix_map: vec![("DB".into(), "database".into())],
+            ..Default::default()
+        };
+        let names = ["APP__A__B", "DB__PASSWORD", "OLD__C", "OTHER"];
+        let names = BTreeSet::from(names.map(String::from));
+        assert_eq!(
+            env_secret_keys(&names, &options),
+            ["a.b", "database.password", "c"]
+        );
+        let names = BTreeSet::from(["A__B".to_owned()]);
+        assert_eq!(env_secret_keys(&names, &InitOptions::default()), ["a.b"]);
+    }
+
+    #[test]
+    fn maps_prefixed_vars_to_nested_keys() {
+        let vars = [("db__port", "5432"), ("db__tls__cert", "/etc/tls/db.pem")];
+        let vars = vars.map(|(name, value)| (name.to_owned(), value.to_owned()));
+        let layer = mapped_env_layer("DB", "database", vars.into_iter().collect())
+            .unwrap()
+            .unwrap();
+        let config = builder_from(&[layer]).build().unwrap();
+        assert_eq!(config.get_int("database.port").unwrap(), 5432);
+        assert_eq!(
+            config.get_string("database.tls.cert").unwrap(),
+            "/etc/tls/db.pem"
+        );
+    }
+
+    #[test]
+    fn redacts_secrets_under_renamed_keys() {
+        let options = InitOptions {
+            key_renames: vec![("db.pass".into(), "db.password".into())],
+            ..Default::default()
+        };
+        let secrets = "db:\n  pass: hunter2\n";
+        let layers = vec![yaml_layer("prod-secrets.yaml", LayerKind::Secret, secrets)];
+        let snapshot = redacted_snapshot(options, layers, &[]);
+        assert!(!snapshot.contains("hunter2"));
+        assert!(snapshot.contains("password"));
+    }
+
+    #[test]
+    fn redacts_secrets_in_the_app_namespace() {
+        let options = InitOptions {
+            app_namespace: Some("app-a".into()),
+            ..Default::default()
+        };
+        let default = "app-a:\n  db:\n    host: db.internal\n";
+        let secrets = "app-a:\n  db:\n    password: hunter2\n";
+        let layers = vec![
+            yaml_layer("default.yaml", LayerKind::File, default),
+            yaml_layer("prod-secrets.yaml", LayerKind::Secret, secrets),
+        ];
+        let snapshot = redacted_snapshot(options, layers, &[]);
+        assert!(!snapshot.contains("hunter2"));
+        assert!(snapshot.contains("db.internal"));
+    }
+
+    #[cfg(feature = "vault")]
+    #[test]
+    fn redacts_values_from_vault() {
+        use std::io::Write;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        env::set_var("VAULT_ADDR", format!("http://{}", listener.local_addr().unwrap()));
+        env::set_var("VAULT_TOKEN", "token");
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut byte = [0];
+            while !request.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            let body = r#"{"data":{"data":{"password":"hunter2"}}}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+
+        let text = "db:\n  password: vault:secret/data/app#password\n";
+        let layers = vec![yaml_layer("prod.yaml", LayerKind::File, text)];
+        let snapshot = redacted_snapshot(InitOptions::default(), layers, &[]);
+        server.join().unwrap();
+        assert!(!snapshot.contains("hunter2"));
+        assert!(snapshot.contains("[REDACTED]"));
+    }
+
+    #[cfg(feature = "vault")]
+    #[test]
+    fn disabled_secrets_skip_vault_references() {
+        let options = InitOptions {
+            secrets_mode: SecretsMode::Disabled,
+            ..Default::default()
+        };
+        let text = "db:\n  host: db.internal\n  password: vault:secret/data/app#password\n";
+        let layers = vec![yaml_layer("prod.yaml", LayerKind::File, text)];
+        let snapshot = redacted_snapshot(options, layers, &[]);
+        assert!(!snapshot.contains("vault:"));
+        assert!(snapshot.contains("db.internal"));
+    }
+
+    // Loads the files given as (path, contents) from the config dir `conf`, with no env vars
+    fn load_files(options: InitOptions, env: Environment, files: &[(&str, &str)]) -> Loaded {
+        load_files_with_vars(options, env, files, &[])
+    }
+
+    // Like load_files(), with the env vars given as (name, value)
+    fn load_files_with_vars(
+        options: InitOptions,
+        env: Environment,
+        files: &[(&str, &str)],
+        vars: &[(&str, &str)],
+    ) -> Loaded {
+        let mut env_vars = Vars::empty();
+        let vars = vars.iter().map(|(name, value)| Ok((name.to_string(), value.to_string())));
+        env_vars.load(vars).unwrap();
+        let init = Init {
+            options: InitOptions {
+                file_provider: Some(Arc::new(files::MemFs::new(files))),
+                quiet: true,
+                ..options
+            },
+            customize: Box::new(|config_builder| config_builder),
+            base: None,
+            build_script: false,
+        };
+        load(&init, "conf".into(), env, env_vars, vec![]).unwrap()
+    }
+
+    #[test]
+    fn reads_file_refs_like_the_config_files() {
+        let files = files::MemFs::new(&[("conf/cert.pem", "\u{feff}cert")]);
+        let options = InitOptions {
+            file_provider: Some(Arc::new(files)),
+            ..Default::default()
+        };
+        let text = "cert: !file cert.pem\n";
+        let layers = vec![yaml_layer("conf/default.yaml", LayerKind::File, text)];
+        let config = || builder_from(&layers).build().unwrap();
+        let info = LoadInfo {
+            config_dir: "conf".into(),
+            ..Default::default()
+        };
+        let resolved = apply_transforms(config(), &info, &options).unwrap();
+        assert_eq!(resolved.get_string("cert").unwrap(), "cert");
+
+        let options = InitOptions {
+            reproducible: Some(Reproducible {
+                env: Environment::Dev,
+                prefixed_env_vars: false,
+                files: strings(&["default.yaml"]),
+            }),
+            ..options
+        };
+        assert!(apply_transforms(config(), &info, &options).is_err());
+    }
+
+    #[test]
+    fn reload_ignores_env_and_config_dir_exported_from_env_files() {
+        let files = [("./conf/.env", "ENV=prod\nCONFIG_DIR=./other\n")];
+        let init = Init {
+            options: InitOptions {
+                file_provider: Some(Arc::new(files::MemFs::new(&files))),
+                quiet: true,
+                ..Default::default()
+            },
+            customize: Box::new(|config_builder| config_builder),
+            base: None,
+            build_script: false,
+        };
+        let first = read_config_vars_from_all_sources(&init, vec![]).unwrap();
+        let reloaded = read_config_vars_from_all_sources(&init, vec![]).unwrap();
+        assert_eq!(reloaded.env, first.env);
+        assert_eq!(reloaded.info.config_dir, first.info.config_dir);
+    }
+
+    #[test]
+    fn reads_secret_env_vars_only_as_their_keys() {
+        let options = InitOptions {
+            secret_env_vars: vec![("DB_PASSWORD".into(), "database.password".into())],
+            ..Default::default()
+        };
+        let vars = [("DB_PASSWORD", "hunter2"), ("DB_HOST", "db.internal")];
+        let loaded = load_files_with_vars(options, Environment::Dev, &[], &vars);
+        assert_eq!(loaded.config.get_string("database.password").unwrap(), "hunter2");
+        assert!(loaded.config.get_string("db_password").is_err());
+        assert_eq!(loaded.config.get_string("db_host").unwrap(), "db.internal");
+    }
+
+    #[test]
+    fn disabled_secrets_skip_secret_env_vars() {
+        let options = InitOptions {
+            secret_env_vars: vec![("DB_PASSWORD".into(), "database.password".into())],
+            secrets_mode: SecretsMode::Disabled,
+            ..Default::default()
+        };
+        let vars = [("DB_PASSWORD", "hunter2")];
+        let loaded = load_files_with_vars(options, Environment::Dev, &[], &vars);
+        assert!(loaded.config.get_string("database.password").is_err());
+    }
+
+    #[test]
+    fn defaults_only_loads_only_the_default_files() {
+        let files = [
+            ("conf/default.yaml", "a: default.yaml\n"),
+            ("conf/default.env", "B=default.env\n"),
+            ("conf/dev.yaml", "c: dev.yaml\n"),
+            ("conf/dev.env", "D=dev.env\n"),
+            ("conf/local.yaml", "e: local.yaml\n"),
+            ("conf/local.env", "F=local.env\n"),
+            ("conf/.env", "G=.env\n"),
+            ("conf/local-overrides.yaml", "h: local-overrides.yaml\n"),
+            ("conf/profile-debug.yaml", "i: profile-debug.yaml\n"),
+        ];
+        let options = InitOptions {
+            defaults_only: true,
+            profiles: Some(strings(&["debug"])),
+            ..Default::default()
+        };
+        let loaded = load_files(options, Environment::Dev, &files);
+        let mut keys: Vec<String> = loaded.config.collect().unwrap().into_keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "b"]);
+    }
+
+    #[test]
+    fn local_overrides_are_only_loaded_in_dev() {
+        let files = [
+            ("conf/default.yaml", "a: default.yaml\n"),
+            ("conf/local-overrides.yaml", "a: local-overrides.yaml\n"),
+        ];
+        let value = |env| {
+            let loaded = load_files(InitOptions::default(), env, &files);
+            loaded.config.get_string("a").unwrap()
+        };
+        assert_eq!(value(Environment::Dev), "local-overrides.yaml");
+        assert_eq!(value(Environment::Prod), "default.yaml");
+    }
+
+    #[test]
+    fn loads_files_in_order_of_precedence() {
+        // From highest to lowest precedence, each level setting the keys of its own level and
+        // every level above it, so each key ends up with the value of its own level
+        let levels = [
+            "local-overrides.yaml",
+            ".env",
+            "local.env",
+            "dev.env",
+            "default.env",
+            "local.yaml",
+            "profile-debug.yaml",
+            "dev.yaml",
+            "default.yaml",
+        ];
+        let contents: Vec<(String, String)> = levels
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let text: String = (0..=i)
+                    .map(|key| {
+                        if name.ends_with(".yaml") {
+                            format!("k{key}: {name}\n")
+                        } else {
+                            format!("K{key}={name}\n")
+                        }
+                    })
+                    .collect();
+                (format!("conf/{name}"), text)
+            })
+            .collect();
+        let files: Vec<(&str, &str)> = contents
+            .iter()
+            .map(|(path, text)| (path.as_str(), text.as_str()))
+            .collect();
+        let options = InitOptions {
+            profiles: Some(strings(&["debug"])),
+            ..Default::default()
+        };
+        let loaded = load_files(options, Environment::Dev, &files);
+        for (i, name) in levels.iter().enumerate() {
+            assert_eq!(loaded.config.get_string(&format!("k{i}")).unwrap(), *name);
+        }
+    }
+
+    #[test]
+    fn matches_patterns_with_wildcards() {
+        assert!(matches_pattern("db.password", "db.password"));
+        assert!(!matches_pattern("db.password", "db.password2"));
+        assert!(matches_pattern("*.password", "db.password"));
+        assert!(matches_pattern("db.*", "db.password"));
+        assert!(matches_pattern("*token*", "auth.token_ttl"));
+        assert!(matches_pattern("a*b*c", "abc"));
+        assert!(!matches_pattern("a*b*c", "acb"));
+        assert!(!matches_pattern("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn splits_lists_on_unescaped_commas() {
+        assert_eq!(split_list("a,b,c"), strings(&["a", "b", "c"]));
+        assert_eq!(split_list(r"a,b\,c"), strings(&["a", "b,c"]));
+        assert_eq!(split_list(r"a\\,b"), strings(&[r"a\", "b"]));
+        assert_eq!(split_list(r"a\nb"), strings(&[r"a\nb"]));
+        assert_eq!(split_list(r"a\"), strings(&[r"a\"]));
+    }
+
+    #[test]
+    fn merges_arrays_across_layers() {
+        let layers = vec![
+            yaml_layer("default.yaml", LayerKind::File, "hosts: [a, b]\nports: [1]\n"),
+            yaml_layer("prod.yaml", LayerKind::File, "hosts: [b, c]\nports: 2\n"),
+            yaml_layer("local.yaml", LayerKind::File, "ports: [3]\n"),
+        ];
+        let merged = |strategy| {
+            let options = InitOptions {
+                array_merge: vec![("hosts".into(), strategy), ("ports".into(), strategy)],
+                ..Default::default()
+            };
+            let config = builder_from(&layers).build().unwrap();
+            let config = merge_arrays(config, &layers, &options).unwrap();
+            let hosts: Vec<String> = config.get("hosts").unwrap();
+            let ports: Vec<i64> = config.get("ports").unwrap();
+            (hosts, ports)
+        };
+        assert_eq!(merged(MergeStrategy::Append), (strings(&["a", "b", "b", "c"]), vec![3]));
+        assert_eq!(merged(MergeStrategy::Unique), (strings(&["a", "b", "c"]), vec![3]));
+        assert_eq!(merged(MergeStrategy::Replace), (strings(&["b", "c"]), vec![3]));
+    }
+
+    #[test]
+    fn joins_lists_that_split_back_the_same() {
+        let elements = strings(&["a,b", r"c\", r"d\,e", r"f\n"]);
+        let joined = join_list(elements.iter().cloned());
+        assert_eq!(joined, r"a\,b,c\\,d\\\,e,f\\n");
+        assert_eq!(split_list(&joined), elements);
+    }
+
+    #[test]
+    fn keeps_empty_list_elements() {
+        assert_eq!(split_list(""), Vec::<String>::new());
+        assert_eq!(split_list(","), strings(&["", ""]));
+        assert_eq!(split_list("a,,b"), strings(&["a", "", "b"]));
+    }
+
+    #[test]
+    fn list_elements_keep_their_text() {
+        let mut env_vars = config::Map::new();
+        env_vars.insert("app__ids".to_owned(), "007,010,1.10,3,true".to_owned());
+        let key = "ids".to_owned();
+        let table = take_list_vars(&mut env_vars, &[&key], Some("APP"));
+        assert!(env_vars.is_empty());
+        let config::ValueKind::Array(elements) = &table["ids"].kind else {
+            panic!("ids is not a list");
+        };
+        let kinds: Vec<&config::ValueKind> = elements.iter().map(|value| &value.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                &config::ValueKind::String("007".into()),
+                &config::ValueKind::String("010".into()),
+                &config::ValueKind::String("1.10".into()),
+                &config::ValueKind::I64(3),
+                &config::ValueKind::Boolean(true),
+            ]
+        );
     }
 }
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::files::FileProvider;
+
+// The manifest uses the same format as `sha256sum` output, one `<hex digest>  <file name>`
+// entry per line, with file names relative to the config directory. Files outside of it (eg. in
+// base_dir) are listed by their full path
+#[derive(Debug)]
+pub(crate) struct Manifest {
+    path: String,
+    config_dir: PathBuf,
+    // Hex digests by file name
+    entries: HashMap<String, String>,
+}
+
+impl Manifest {
+    pub(crate) fn read(path: &str, config_dir: &str, files: &dyn FileProvider) -> Result<Self> {
+        let manifest = files
+            .read(Path::new(path))
+            .with_context(|| format!("Failed to read config manifest {path}"))?;
+        Ok(Self::parse(path, config_dir, &String::from_utf8_lossy(&manifest)))
+    }
+
+    fn parse(path: &str, config_dir: &str, manifest: &str) -> Self {
+        let entries = manifest
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (hash, name) = line.split_once(char::is_whitespace)?;
+                // `sha256sum -b` marks binary mode with a leading `*` on the file name
+                let name = name.trim().trim_start_matches('*');
+                Some((name.to_owned(), hash.to_owned()))
+            })
+            .collect();
+        Self {
+            path: path.to_owned(),
+            config_dir: config_dir.into(),
+            entries,
+        }
+    }
+
+    // Checks the contents read from the config file at `path` against its entry
+    fn verify(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        let name = path.strip_prefix(&self.config_dir).unwrap_or(path);
+        let Some(expected_hash) = self.entries.get(&*name.to_string_lossy()) else {
+            bail!(
+                "{} is not listed in config manifest {}",
+                path.display(),
+                self.path
+            );
+        };
+        let actual_hash = format!("{:x}", Sha256::digest(contents));
+        if !actual_hash.eq_ignore_ascii_case(expected_hash) {
+            bail!(
+                "{} does not match its checksum in config manifest {}",
+                path.display(),
+                self.path
+            );
+        }
+        Ok(())
+    }
+}
+
+// Verifies every file as it's read, so whatever ends up in a layer has been checked (files that
+// aren't present are never loaded, so there is nothing to verify). A file that fails is reported
+// as unreadable, and the failure is kept for check() too, since the loader skips some files that
+// can't be read. There are no mtimes, so encrypted files are read (and verified) on every load
+// rather than taken from the secrets cache, and no local paths, so they are decrypted from the
+// contents that were verified rather than read again
+#[derive(Debug)]
+pub(crate) struct VerifiedFiles<'a> {
+    files: &'a dyn FileProvider,
+    manifest: &'a Manifest,
+    failures: Mutex<Vec<String>>,
+}
+
+impl<'a> VerifiedFiles<'a> {
+    pub(crate) fn new(files: &'a dyn FileProvider, manifest: &'a Manifest) -> Self {
+        Self {
+            files,
+            manifest,
+            failures: Mutex::new(vec![]),
+        }
+    }
+
+    // Fails if any file read so far didn't match the manifest
+    pub(crate) fn check(&self) -> Result<()> {
+        let failures = self.failures.lock().unwrap();
+        if !failures.is_empty() {
+            bail!(
+                "Config files failed verification:\n  {}",
+                failures.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+}
+
+impl FileProvider for VerifiedFiles<'_> {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let contents = self.files.read(path)?;
+        if let Err(e) = self.manifest.verify(path, &contents) {
+            self.failures.lock().unwrap().push(e.to_string());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+        }
+        Ok(contents)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.files.canonicalize(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> io::Result<bool> {
+        self.files.is_dir(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "\
+# generated at release
+2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae  default.yaml
+FCDE2B2EDBA56BF408601FB721FE9B5C338D10EE429EA04FAE5511B68FBF8FB9 *prod/prod.yaml
+
+baa5a0964d3320fbc0c6a922140453c8513ea24ab8fd0577034804a967248096  /etc/shared/default.yaml
+";
+
+    fn manifest() -> Manifest {
+        Manifest::parse("conf/MANIFEST", "conf", MANIFEST)
+    }
+
+    #[test]
+    fn parses_sha256sum_output() {
+        let manifest = manifest();
+        let mut names: Vec<&str> = manifest.entries.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            ["/etc/shared/default.yaml", "default.yaml", "prod/prod.yaml"]
+        );
+    }
+
+    #[test]
+    fn verifies_files_by_their_path_in_the_config_dir() {
+        let manifest = manifest();
+        assert!(manifest.verify(Path::new("conf/default.yaml"), b"foo").is_ok());
+        assert!(manifest.verify(Path::new("conf/prod/prod.yaml"), b"bar").is_ok());
+        assert!(manifest
+            .verify(Path::new("/etc/shared/default.yaml"), b"baz")
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_changed_and_unlisted_files() {
+        let manifest = manifest();
+        let changed = manifest.verify(Path::new("conf/default.yaml"), b"changed");
+        assert_eq!(
+            changed.unwrap_err().to_string(),
+            "conf/default.yaml does not match its checksum in config manifest conf/MANIFEST"
+        );
+        let unlisted = manifest.verify(Path::new("conf/local.yaml"), b"foo");
+        assert_eq!(
+            unlisted.unwrap_err().to_string(),
+            "conf/local.yaml is not listed in config manifest conf/MANIFEST"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use config::{Value, ValueKind};
+
+// Sets a scalar value in a block-style yaml file by editing just the line holding the key (or
+// inserting lines for it), so the comments and formatting of the rest of the file survive
+pub(crate) fn set_yaml_value(path: &Path, key: &str, value: &Value) -> Result<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let mut lines: Vec<String> = contents.lines().map(str::to_owned).collect();
+    set_line(&mut lines, key, &render_scalar(key, value)?)?;
+
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    write_atomic(path, &updated)
+}
+
+// Writes to a temp file next to `path` and renames it into place, so that a reader never sees a
+// partly written file
+pub(crate) fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp{}", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, contents).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e).with_context(|| format!("Failed to write {}", path.display()));
+    }
+    Ok(())
+}
+
+fn set_line(lines: &mut Vec<String>, key: &str, rendered: &str) -> Result<()> {
+    let segments: Vec<&str> = key.split('.').collect();
+    // The lines of the mapping we're currently looking in, and the indent of its keys
+    let mut start = 0;
+    let mut end = lines.len();
+    let mut indent = 0;
+
+    for (depth, segment) in segments.iter().enumerate() {
+        if let Some(first) = (start..end).find(|&i| is_content(&lines[i])) {
+            indent = indent_of(&lines[first]);
+        }
+        let found = (start..end).find(|&i| {
+            is_content(&lines[i])
+                && indent_of(&lines[i]) == indent
+                && split_key(&lines[i]).is_some_and(|(k, _)| k == *segment)
+        });
+
+        let Some(line) = found else {
+            // Insert the missing part of the key at the end of the current mapping, before any
+            // blank lines separating it from what follows
+            let mut insert_at = end;
+            while insert_at > start && lines[insert_at - 1].trim().is_empty() {
+                insert_at -= 1;
+            }
+            for (offset, segment) in segments[depth..].iter().enumerate() {
+                let pad = " ".repeat(indent + 2 * offset);
+                let new_line = if depth + offset == segments.len() - 1 {
+                    format!("{pad}{segment}: {rendered}")
+                } else {
+                    format!("{pad}{segment}:")
+                };
+                lines.insert(insert_at + offset, new_line);
+            }
+            return Ok(());
+        };
+
+        let block_end = (line + 1..end)
+            .find(|&i| is_content(&lines[i]) && indent_of(&lines[i]) <= indent)
+            .unwrap_or(end);
+        let has_children = (line + 1..block_end).any(|i| is_content(&lines[i]));
+        let (_, rest) = split_key(&lines[line]).unwrap();
+        let inline_value = !rest.trim().is_empty() && !rest.trim_start().starts_with('#');
+
+        if depth == segments.len() - 1 {
+            if has_children {
+                bail!("{key} is a section, only scalar values can be persisted");
+            }
+            let comment = trailing_comment(rest);
+            let updated = format!("{}{segment}: {rendered}{comment}", " ".repeat(indent));
+            lines[line] = updated;
+            return Ok(());
+        }
+        if inline_value {
+            bail!("{} is not a section", segments[..=depth].join("."));
+        }
+        start = line + 1;
+        end = block_end;
+        indent += 2;
+    }
+
+    Ok(())
+}
+
+fn is_content(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    !trimmed.is_empty() && !trimmed.starts_with('#')
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+// Splits `  key: value` into the (unquoted) key and everything after the colon
+fn split_key(line: &str) -> Option<(&str, &str)> {
+    let (key, rest) = line.trim_start().split_once(':')?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some((key.trim().trim_matches(|c| c == '"' || c == '\''), rest))
+}
+
+// The ` # comment` after an unquoted value, if any
+fn trailing_comment(rest: &str) -> &str {
+    if rest.trim_start().starts_with(['"', '\'']) {
+        return "";
+    }
+    rest.find(" #").map_or("", |pos| &rest[pos..])
+}
+
+fn render_scalar(key: &str, value: &Value) -> Result<String> {
+    Ok(match &value.kind {
+        ValueKind::Nil => "null".into(),
+        ValueKind::Boolean(v) => v.to_string(),
+        ValueKind::I64(v) => v.to_string(),
+        ValueKind::I128(v) => v.to_string(),
+        ValueKind::U64(v) => v.to_string(),
+        ValueKind::U128(v) => v.to_string(),
+        ValueKind::Float(v) if v.is_finite() => format!("{v:?}"),
+        ValueKind::Float(v) => bail!("Can't persist {v} for {key}"),
+        ValueKind::String(v) => {
+            let escaped = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('\t', "\\t");
+            format!("\"{escaped}\"")
+        }
+        ValueKind::Table(_) | ValueKind::Array(_) => {
+            bail!("Only scalar values can be persisted, {key} is a table or array")
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(text: &str, key: &str, rendered: &str) -> Result<String> {
+        let mut lines: Vec<String> = text.lines().map(str::to_owned).collect();
+        set_line(&mut lines, key, rendered)?;
+        Ok(lines.join("\n") + "\n")
+    }
+
+    #[test]
+    fn edits_nested_keys_in_place() {
+        let text = "\
+# Local settings
+server:
+  # Where to listen
+  host: localhost
+  port: 8080 # the default
+db:
+  port: 5432
+";
+        assert_eq!(
+            set(text, "server.port", "9090").unwrap(),
+            text.replace("8080 #", "9090 #")
+        );
+        assert_eq!(
+            set(text, "db.port", "6432").unwrap(),
+            text.replace("5432", "6432")
+        );
+        assert_eq!(
+            set(text, "server.host", "\"0.0.0.0\"").unwrap(),
+            text.replace("localhost", "\"0.0.0.0\"")
+        );
+    }
+
+    #[test]
+    fn inserts_keys_that_dont_exist_yet() {
+        let text = "server:\n  port: 8080\n\ndb:\n  url: x\n";
+        assert_eq!(
+            set(text, "server.tls.cert", "\"a.pem\"").unwrap(),
+            "server:\n  port: 8080\n  tls:\n    cert: \"a.pem\"\n\ndb:\n  url: x\n"
+        );
+        assert_eq!(
+            set(text, "cache.size", "5").unwrap(),
+            format!("{text}cache:\n  size: 5\n")
+        );
+        assert_eq!(set("", "debug", "true").unwrap(), "debug: true\n");
+    }
+
+    #[test]
+    fn rejects_keys_that_arent_scalars() {
+        let text = "server:\n  port: 8080\n";
+        let section = set(text, "server", "1").unwrap_err();
+        assert_eq!(
+            section.to_string(),
+            "server is a section, only scalar values can be persisted"
+        );
+        let scalar = set(text, "server.port.number", "1").unwrap_err();
+        assert_eq!(scalar.to_string(), "server.port is not a section");
+    }
+
+    #[test]
+    fn renders_strings_quoted() {
+        let value = Value::new(None, "say \"hi\"\n");
+        assert_eq!(
+            render_scalar("greeting", &value).unwrap(),
+            r#""say \"hi\"\n""#
+        );
+    }
+}
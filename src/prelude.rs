@@ -0,0 +1,85 @@
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use config::{FileFormat, Source};
+
+use crate::files::{self, FileProvider};
+use crate::vars;
+
+const PATH: &str = "loader.toml";
+
+// The loader's own env vars that loader.toml can set. CONFIG_MANIFEST is left out on purpose, like
+// it is for .env files
+pub(crate) const VARS: [&str; 4] = ["ENV", "CONFIG_DIR", "SECRETS_ENCRYPTION_KEY", "PROFILES"];
+
+// Settings from loader.toml in the working dir, by the env var each one stands in for. Its keys
+// are the names of those env vars in lowercase, eg. `config_dir = "/etc/app"`. A missing file has
+// no settings
+pub(crate) fn read(files: &dyn FileProvider) -> Result<Vec<(String, String)>> {
+    let contents = match files.read(Path::new(PATH)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context("Failed to read loader.toml"),
+    };
+    let text = files::text(PATH, contents)?;
+    let table = config::File::from_str(&text, FileFormat::Toml)
+        .collect()
+        .context("Failed to parse loader.toml")?;
+
+    let mut settings = vec![];
+    for (key, value) in table {
+        let Some(var) = VARS.iter().find(|var| var.eq_ignore_ascii_case(&key)) else {
+            bail!(
+                "Unknown setting {key} in loader.toml, expected one of {}",
+                VARS.map(str::to_lowercase).join(", ")
+            );
+        };
+        let value = value
+            .into_string()
+            .with_context(|| format!("Setting {key} in loader.toml is not a string"))?;
+        settings.push(((*var).to_owned(), value));
+    }
+    Ok(settings)
+}
+
+// The process env var `name` (leaving out vars exported from .env files by earlier loads), or
+// else its setting in loader.toml
+pub(crate) fn var(name: &str, files: &dyn FileProvider) -> Result<Option<String>> {
+    if let Some(value) = vars::process_var(name) {
+        return Ok(Some(value));
+    }
+    Ok(read(files)?
+        .into_iter()
+        .find(|(var, _)| var == name)
+        .map(|(_, value)| value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::MemFs;
+
+    #[test]
+    fn reads_settings_by_env_var() {
+        let files = MemFs::new(&[(PATH, "env = \"prod\"\nCONFIG_DIR = \"/etc/app\"\n")]);
+        let mut settings = read(&files).unwrap();
+        settings.sort();
+        assert_eq!(
+            settings,
+            [
+                ("CONFIG_DIR".to_owned(), "/etc/app".to_owned()),
+                ("ENV".to_owned(), "prod".to_owned())
+            ]
+        );
+        assert!(read(&MemFs::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_and_non_string_settings() {
+        let unknown = MemFs::new(&[(PATH, "config_manifest = \"m\"\n")]);
+        assert!(read(&unknown).is_err());
+        let array = MemFs::new(&[(PATH, "env = [\"prod\"]\n")]);
+        assert!(read(&array).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use config::{FileFormat, Map, Value, ValueKind};
+use yaml_rust2::yaml::Hash;
+use yaml_rust2::{Yaml, YamlEmitter};
+
+// Keys are written in sorted order, so rendering the same config always gives the same output
+pub(crate) fn render(table: &Map<String, Value>, format: FileFormat) -> Result<String> {
+    let mut out = String::new();
+    match format {
+        FileFormat::Yaml => {
+            YamlEmitter::new(&mut out).dump(&yaml_table(table))?;
+            out.push('\n');
+        }
+        FileFormat::Json => {
+            json_table(table, 0, &mut out);
+            out.push('\n');
+        }
+        format => bail!("Rendering config as {format:?} is not supported, use yaml or json"),
+    }
+    Ok(out)
+}
+
+fn sorted(table: &Map<String, Value>) -> BTreeMap<&String, &Value> {
+    table.iter().collect()
+}
+
+fn yaml_table(table: &Map<String, Value>) -> Yaml {
+    let mut hash = Hash::new();
+    for (key, value) in sorted(table) {
+        hash.insert(Yaml::String(key.clone()), yaml_value(value));
+    }
+    Yaml::Hash(hash)
+}
+
+fn yaml_value(value: &Value) -> Yaml {
+    match &value.kind {
+        ValueKind::Nil => Yaml::Null,
+        ValueKind::Boolean(v) => Yaml::Boolean(*v),
+        ValueKind::I64(v) => Yaml::Integer(*v),
+        ValueKind::I128(v) => Yaml::Real(v.to_string()),
+        ValueKind::U64(v) => Yaml::Real(v.to_string()),
+        ValueKind::U128(v) => Yaml::Real(v.to_string()),
+        ValueKind::Float(v) if v.is_nan() => Yaml::Real(".nan".into()),
+        ValueKind::Float(v) if v.is_infinite() => {
+            Yaml::Real(if *v > 0.0 { ".inf" } else { "-.inf" }.into())
+        }
+        ValueKind::Float(v) => Yaml::Real(format!("{v:?}")),
+        ValueKind::String(v) => Yaml::String(v.clone()),
+        ValueKind::Table(table) => yaml_table(table),
+        ValueKind::Array(array) => Yaml::Array(array.iter().map(yaml_value).collect()),
+    }
+}
+
+fn json_table(table: &Map<String, Value>, indent: usize, out: &mut String) {
+    if table.is_empty() {
+        out.push_str("{}");
+        return;
+    }
+    out.push_str("{\n");
+    for (i, (key, value)) in sorted(table).into_iter().enumerate() {
+        if i > 0 {
+            out.push_str(",\n");
+        }
+        out.push_str(&"  ".repeat(indent + 1));
+        json_string(key, out);
+        out.push_str(": ");
+        json_value(value, indent + 1, out);
+    }
+    out.push('\n');
+    out.push_str(&"  ".repeat(indent));
+    out.push('}');
+}
+
+fn json_value(value: &Value, indent: usize, out: &mut String) {
+    match &value.kind {
+        ValueKind::Nil => out.push_str("null"),
+        ValueKind::Boolean(v) => out.push_str(&v.to_string()),
+        ValueKind::I64(v) => out.push_str(&v.to_string()),
+        ValueKind::I128(v) => out.push_str(&v.to_string()),
+        ValueKind::U64(v) => out.push_str(&v.to_string()),
+        ValueKind::U128(v) => out.push_str(&v.to_string()),
+        // JSON has no representation for NaN or infinity
+        ValueKind::Float(v) if !v.is_finite() => out.push_str("null"),
+        ValueKind::Float(v) => out.push_str(&format!("{v:?}")),
+        ValueKind::String(v) => json_string(v, out),
+        ValueKind::Table(table) => json_table(table, indent, out),
+        ValueKind::Array(array) if array.is_empty() => out.push_str("[]"),
+        ValueKind::Array(array) => {
+            out.push_str("[\n");
+            for (i, value) in array.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(",\n");
+                }
+                out.push_str(&"  ".repeat(indent + 1));
+                json_value(value, indent + 1, out);
+            }
+            out.push('\n');
+            out.push_str(&"  ".repeat(indent));
+            out.push(']');
+        }
+    }
+}
+
+pub(crate) fn json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -0,0 +1,144 @@
+use schemars::JsonSchema;
+use serde_json::Value;
+
+// JSON schema of a config struct, for rendering reference docs listing every key with its type
+// and default
+pub fn schema_of<T: JsonSchema>() -> Value {
+    serde_json::to_value(schemars::schema_for!(T)).expect("JSON schemas always serialize")
+}
+
+// A commented `.env.example` listing the env var for every leaf key of `T`, eg.
+// `APP__SERVER__PORT=` with its type (and description, if any) as comments
+pub fn env_template<T: JsonSchema>(prefix: &str, separator: &str) -> String {
+    let root = schema_of::<T>();
+    let mut template = String::new();
+    write_env_lines(&root, &root, &mut vec![], prefix, separator, &mut template);
+    template
+}
+
+// The dotted keys of every list of scalars in `T`, for InitOptions::list_parse_keys, so the
+// keys whose env vars are split into lists don't have to be kept in sync with the struct by hand
+pub fn list_parse_keys_of<T: JsonSchema>() -> Vec<String> {
+    let root = schema_of::<T>();
+    let mut keys = vec![];
+    collect_list_keys(&root, &root, &mut vec![], &mut keys);
+    keys
+}
+
+fn collect_list_keys(root: &Value, schema: &Value, path: &mut Vec<String>, keys: &mut Vec<String>) {
+    let resolved = resolve(root, schema);
+    if let Some(properties) = resolved.get("properties").and_then(Value::as_object) {
+        for (key, property) in properties {
+            path.push(key.clone());
+            collect_list_keys(root, property, path, keys);
+            path.pop();
+        }
+        return;
+    }
+    if path.is_empty() || !has_type(resolved, "array") {
+        return;
+    }
+    // Lists of tables can't be given as comma separated values anyway
+    let items = resolved.get("items").map(|items| resolve(root, items));
+    if items.is_some_and(|items| has_type(items, "object")) {
+        return;
+    }
+    keys.push(path.join("."));
+}
+
+fn has_type(schema: &Value, ty: &str) -> bool {
+    match schema.get("type") {
+        Some(Value::String(t)) => t == ty,
+        Some(Value::Array(types)) => types.iter().any(|t| t.as_str() == Some(ty)),
+        _ => false,
+    }
+}
+
+fn write_env_lines(
+    root: &Value,
+    schema: &Value,
+    path: &mut Vec<String>,
+    prefix: &str,
+    separator: &str,
+    template: &mut String,
+) {
+    let resolved = resolve(root, schema);
+    if let Some(properties) = resolved.get("properties").and_then(Value::as_object) {
+        for (key, property) in properties {
+            path.push(key.to_uppercase());
+            write_env_lines(root, property, path, prefix, separator, template);
+            path.pop();
+        }
+        return;
+    }
+    if path.is_empty() {
+        return;
+    }
+
+    let description = schema
+        .get("description")
+        .or_else(|| resolved.get("description"))
+        .and_then(Value::as_str);
+    for line in description.into_iter().flat_map(str::lines) {
+        template.push_str(&format!("# {line}\n"));
+    }
+    let var = if prefix.is_empty() {
+        path.join(separator)
+    } else {
+        format!("{prefix}{separator}{}", path.join(separator))
+    };
+    template.push_str(&format!("# {}\n{var}=\n\n", type_hint(resolved)));
+}
+
+// Follows `$ref`s, along with the single schema wrappers schemars emits for references with a
+// description (`allOf`) and for Options (`anyOf` with null)
+fn resolve<'a>(root: &'a Value, schema: &'a Value) -> &'a Value {
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        if let Some(target) = root.pointer(reference.trim_start_matches('#')) {
+            return resolve(root, target);
+        }
+    }
+    for wrapper in ["allOf", "anyOf", "oneOf"] {
+        let Some(variants) = schema.get(wrapper).and_then(Value::as_array) else {
+            continue;
+        };
+        let not_null: Vec<&Value> = variants
+            .iter()
+            .filter(|variant| variant.get("type").and_then(Value::as_str) != Some("null"))
+            .collect();
+        if let [only] = not_null.as_slice() {
+            return resolve(root, only);
+        }
+    }
+    schema
+}
+
+fn type_hint(schema: &Value) -> String {
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        let values: Vec<String> = values
+            .iter()
+            .map(|value| {
+                value
+                    .as_str()
+                    .map_or_else(|| value.to_string(), str::to_owned)
+            })
+            .collect();
+        return format!("one of {}", values.join(", "));
+    }
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(ty)) => vec![ty.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    let optional = types.contains(&"null");
+    let hint = match types.iter().find(|ty| **ty != "null") {
+        Some(&"array") => "list (comma-separated)".to_owned(),
+        Some(ty) => (*ty).to_owned(),
+        None => "any".to_owned(),
+    };
+    if optional {
+        format!("{hint}, optional")
+    } else {
+        hint
+    }
+}
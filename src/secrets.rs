@@ -0,0 +1,333 @@
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use config::{ConfigError, Map, Source, Value, ValueKind};
+use sha2::{Digest, Sha256};
+use simple_encrypt::{decrypt_file, decrypt_string};
+
+use crate::files::FileProvider;
+use crate::tree;
+use crate::yaml::BlockScalars;
+
+// Marks a single encrypted value in an otherwise plaintext yaml file, eg.
+// `password: !secret <ciphertext>`
+const SECRET_TAG: &str = "!secret ";
+
+// Prefixes the plaintext of a decrypted `!secret` value until take_decrypted() strips it after
+// merging, so the keys that hold secrets are known for redacting them
+pub(crate) const DECRYPTED: &str = "\u{1}simple_config_loader_secret:";
+
+// What a `!secret` value is replaced with when there are no keys to decrypt it with, so that
+// yaml::layers() drops it, as if the value weren't there at all
+const SKIPPED: &str = "\u{1}simple_config_loader_skipped_secret";
+
+struct Cached {
+    // The modification time and size of the file, if its FileProvider has them
+    stamp: Option<(SystemTime, u64)>,
+    digest: Vec<u8>,
+    keys: Vec<String>,
+    decrypted: Vec<u8>,
+    key_index: usize,
+}
+
+// Decrypted files by path, so reload() only decrypts files that changed since
+static CACHE: OnceLock<Mutex<HashMap<String, Cached>>> = OnceLock::new();
+
+// SECRETS_ENCRYPTION_KEY may hold several comma-separated keys while rotating keys (base64 never
+// contains a comma), eg. `<new key>,<old key>`
+pub(crate) fn parse_keys(keys: &str) -> Vec<String> {
+    keys.split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+// The plaintext and key index cached for the file at `path`, if its modification time and size
+// are still `stamp` and the keys are unchanged, so that an unchanged file isn't even read again
+pub(crate) fn cached(
+    path: &str,
+    stamp: (SystemTime, u64),
+    keys: &[String],
+) -> Option<(Vec<u8>, usize)> {
+    let cache = CACHE.get()?.lock().unwrap();
+    let cached = cache.get(path)?;
+    (cached.stamp == Some(stamp) && cached.keys == keys)
+        .then(|| (cached.decrypted.clone(), cached.key_index))
+}
+
+// Decrypts the contents of the file at `path` with the first key that works, returning the
+// plaintext along with the index of that key. The result is reused as long as the contents and the
+// keys are unchanged, going by the digest of the contents (which also covers files whose mtime
+// changed without their contents changing). `stamp` is kept for cached(), and `files` is where
+// the contents were read from
+pub(crate) fn decrypt(
+    path: &str,
+    contents: &[u8],
+    keys: &[String],
+    stamp: Option<(SystemTime, u64)>,
+    files: &dyn FileProvider,
+) -> Option<(Vec<u8>, usize)> {
+    let digest = Sha256::digest(contents).to_vec();
+    let mut cache = CACHE.get_or_init(Default::default).lock().unwrap();
+    if let Some(cached) = cache.get_mut(path) {
+        if cached.digest == digest && cached.keys == keys {
+            cached.stamp = stamp;
+            return Some((cached.decrypted.clone(), cached.key_index));
+        }
+    }
+
+    let (decrypted, key_index) = decrypt_contents(Path::new(path), contents, keys, files)?;
+    cache.insert(
+        path.to_owned(),
+        Cached {
+            stamp,
+            digest,
+            keys: keys.to_vec(),
+            decrypted: decrypted.clone(),
+            key_index,
+        },
+    );
+    Some((decrypted, key_index))
+}
+
+// simple_encrypt only decrypts files. Files on the local filesystem are decrypted in place, and
+// other contents go through a temp file in a fresh directory that only we can access, so nothing
+// can be planted at the temp file's path beforehand
+fn decrypt_contents(
+    path: &Path,
+    contents: &[u8],
+    keys: &[String],
+    files: &dyn FileProvider,
+) -> Option<(Vec<u8>, usize)> {
+    let decrypt_with_keys = |file: &Path| {
+        let file = file.to_string_lossy();
+        keys.iter()
+            .enumerate()
+            .find_map(|(index, key)| Some((decrypt_file(&file, key).ok()?, index)))
+    };
+    if let Some(local_path) = files.local_path(path) {
+        return decrypt_with_keys(&local_path);
+    }
+
+    let temp_dir = private_temp_dir().ok()?;
+    let temp_path = temp_dir.join("secrets.enc");
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .and_then(|mut file| file.write_all(contents));
+    let decrypted = written.ok().and_then(|()| decrypt_with_keys(&temp_path));
+    let _ = fs::remove_dir_all(&temp_dir);
+    decrypted
+}
+
+// Creating the directory fails if anything already exists at its path
+fn private_temp_dir() -> io::Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    let dir = env::temp_dir().join(format!(
+        "simple-config-loader-{}-{}-{nanos}",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&dir)?;
+    Ok(dir)
+}
+
+pub(crate) fn clear_cache() {
+    if let Some(cache) = CACHE.get() {
+        cache.lock().unwrap().clear();
+    }
+}
+
+// Replaces every `!secret <ciphertext>` value in a yaml file with its plaintext as a quoted string
+// (marked with DECRYPTED), so only the secret values of a file need to be encrypted and the rest
+// stays readable in diffs
+// Without any keys (no SECRETS_ENCRYPTION_KEY, or SecretsMode::Disabled) the values are skipped
+// rather than failing the load, like encrypted secrets files are
+pub(crate) fn decrypt_inline(path: &str, text: &str, keys: &[String]) -> Result<String> {
+    if !text.contains(SECRET_TAG) {
+        return Ok(text.to_owned());
+    }
+
+    let mut decrypted = String::with_capacity(text.len());
+    let mut block_scalars = BlockScalars::default();
+    for (i, line) in text.lines().enumerate() {
+        let in_block_scalar = block_scalars.contains(line);
+        let tagged = line.find(SECRET_TAG).filter(|&pos| {
+            let before = line[..pos].trim_end();
+            !in_block_scalar && (before.ends_with(':') || before.ends_with('-'))
+        });
+        let Some(pos) = tagged else {
+            decrypted.push_str(line);
+            decrypted.push('\n');
+            continue;
+        };
+
+        let rest = line[pos + SECRET_TAG.len()..].trim_start();
+        let (ciphertext, comment) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let plaintext = if keys.is_empty() {
+            SKIPPED.to_owned()
+        } else {
+            let Some(plaintext) = keys
+                .iter()
+                .find_map(|key| decrypt_string(ciphertext, key).ok())
+            else {
+                bail!(
+                    "Failed to decrypt the !secret value on line {} of {path}",
+                    i + 1
+                );
+            };
+            format!("{DECRYPTED}{plaintext}")
+        };
+        decrypted.push_str(&line[..pos]);
+        decrypted.push_str(&quote(&plaintext));
+        if !comment.is_empty() {
+            decrypted.push(' ');
+            decrypted.push_str(comment);
+        }
+        decrypted.push('\n');
+    }
+    Ok(decrypted)
+}
+
+// Strips the marker from the decrypted `!secret` values in a merged config, returning the paths
+// (as given by tree::visit_leaves_mut()) of the values that had it
+pub(crate) fn take_decrypted(config: config::Config) -> Result<(config::Config, BTreeSet<String>)> {
+    let mut table = config.collect()?;
+    let mut paths = BTreeSet::new();
+    tree::visit_leaves_mut(&mut table, &mut |path, value| {
+        if let ValueKind::String(s) = &mut value.kind {
+            if let Some(plaintext) = s.strip_prefix(DECRYPTED) {
+                *s = plaintext.to_owned();
+                paths.insert(path.to_owned());
+            }
+        }
+        Ok(())
+    })?;
+
+    if paths.is_empty() {
+        return Ok((config, paths));
+    }
+    Ok((tree::rebuild(table)?, paths))
+}
+
+pub(crate) fn is_skipped(value: &Value) -> bool {
+    matches!(&value.kind, ValueKind::String(s) if s == SKIPPED)
+}
+
+// A yaml layer's source without its skipped `!secret` values
+#[derive(Debug, Clone)]
+pub(crate) struct WithoutSkipped(pub(crate) Box<dyn Source + Send + Sync>);
+
+impl Source for WithoutSkipped {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        let mut table = self.0.collect()?;
+        tree::retain_leaves(&mut table, &|_, value| !is_skipped(value));
+        Ok(table)
+    }
+}
+
+pub(crate) fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::LayerKind;
+    use crate::yaml::{self, Document, YamlLimits};
+
+    fn skipped() -> String {
+        quote(SKIPPED)
+    }
+
+    #[test]
+    fn rewrites_tagged_values() {
+        let text = "password: !secret abc # rotated\ntokens:\n  - !secret def\n";
+        assert_eq!(
+            decrypt_inline("prod.yaml", text, &[]).unwrap(),
+            format!("password: {} # rotated\ntokens:\n  - {}\n", skipped(), skipped())
+        );
+    }
+
+    #[test]
+    fn leaves_quoted_tags_and_block_scalars_alone() {
+        let text = "note: \"!secret abc\"\nscript: |\n  password: !secret abc\n\n  - !secret def\n\
+                    items:\n  - key: >-\n      !secret ghi\n    other: !secret jkl\n";
+        let expected = text.replace("other: !secret jkl", &format!("other: {}", skipped()));
+        assert_eq!(decrypt_inline("prod.yaml", text, &[]).unwrap(), expected);
+    }
+
+    #[test]
+    fn fails_on_values_that_dont_decrypt() {
+        let text = "host: localhost\npassword: !secret not-ciphertext\n";
+        let error = decrypt_inline("prod.yaml", text, &["not a key".into()]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to decrypt the !secret value on line 2 of prod.yaml"
+        );
+    }
+
+    #[test]
+    fn skips_secrets_without_keys() {
+        let text = "db:\n  host: localhost\n  password: !secret abc\ntokens:\n  - !secret def\n";
+        let text = decrypt_inline("config/prod.yaml", text, &[]).unwrap();
+        for cross_file_anchors in [false, true] {
+            let document = Document {
+                path: "config/prod.yaml".into(),
+                kind: LayerKind::File,
+                text: text.clone(),
+            };
+            let layers =
+                yaml::layers(vec![document], cross_file_anchors, &YamlLimits::default()).unwrap();
+            let values = layers[0].values().unwrap();
+            assert_eq!(values.keys().collect::<Vec<_>>(), ["db.host", "tokens"]);
+            assert!(matches!(&values["tokens"].kind, ValueKind::Array(array) if array.is_empty()));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn creates_private_temp_dirs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = private_temp_dir().unwrap();
+        let other = private_temp_dir().unwrap();
+        assert_ne!(dir, other);
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        fs::remove_dir(dir).unwrap();
+        fs::remove_dir(other).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+use std::env;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use config::{Source, Value, ValueKind};
+
+use crate::file_refs::FileRefs;
+use crate::files::FileProvider;
+use crate::{tree, InitOptions};
+
+// One step of the pipeline of rewrites applied to every scalar value after merging and before
+// deserialization, given the value's path (eg. `servers[0].host`). The built-in transforms
+// (`!file` values, `${VAR}` references, trimming, `~` expansion) are steps too, and run first
+pub trait ValueTransform: std::fmt::Debug + Send + Sync {
+    fn transform(&self, path: &str, value: &mut Value) -> Result<()>;
+}
+
+// A function applied to every scalar value after merging, given the value's path (eg.
+// `servers[0].host`). It can rewrite the value in place, including changing its type
+#[derive(Clone)]
+pub struct TransformFn(Arc<dyn Fn(&str, &mut Value) + Send + Sync>);
+
+impl TransformFn {
+    pub fn new(f: impl Fn(&str, &mut Value) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for TransformFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TransformFn")
+    }
+}
+
+impl ValueTransform for TransformFn {
+    fn transform(&self, path: &str, value: &mut Value) -> Result<()> {
+        (self.0)(path, value);
+        Ok(())
+    }
+}
+
+// Rewrites the value at one key (a scalar or a whole section) before deserialization, for a field
+// whose format doesn't fit its type's Deserialize impl, eg. a legacy date format
+#[derive(Clone)]
+pub struct KeyTransformFn(Arc<dyn Fn(Value) -> Result<Value> + Send + Sync>);
+
+impl KeyTransformFn {
+    pub fn new(f: impl Fn(Value) -> Result<Value> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for KeyTransformFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("KeyTransformFn")
+    }
+}
+
+// Keys that aren't set are skipped
+pub(crate) fn apply_key_transforms(
+    config: config::Config,
+    options: &InitOptions,
+) -> Result<config::Config> {
+    if options.key_transforms.is_empty() {
+        return Ok(config);
+    }
+
+    let mut table = config.collect()?;
+    for (key, transform) in &options.key_transforms {
+        let Some(value) = tree::remove_path(&mut table, key) else {
+            continue;
+        };
+        let value = (transform.0)(value).with_context(|| format!("Invalid value for {key}"))?;
+        tree::set_path(&mut table, key, value);
+    }
+    tree::rebuild(table)
+}
+
+// The built-in transforms run first, in the order below, then value_transform and then the
+// value_transforms given at init
+pub(crate) fn apply(
+    config: config::Config,
+    config_dir: &Path,
+    files: &dyn FileProvider,
+    options: &InitOptions,
+) -> Result<config::Config> {
+    let mut builtins: Vec<Box<dyn ValueTransform + '_>> =
+        vec![Box::new(FileRefs { config_dir, files })];
+    if options.expand_env_refs {
+        builtins.push(Box::new(ExpandEnvRefs {
+            keep_unresolved: options.keep_unresolved_env_refs,
+        }));
+    }
+    if options.trim_strings {
+        builtins.push(Box::new(TrimStrings));
+    }
+    if options.expand_home {
+        if let Some(home) = env::var("HOME").or_else(|_| env::var("USERPROFILE")).ok() {
+            builtins.push(Box::new(ExpandHome { home }));
+        }
+    }
+    let mut pipeline: Vec<&dyn ValueTransform> = vec![];
+    for transform in &builtins {
+        pipeline.push(transform.as_ref());
+    }
+    if let Some(transform) = &options.value_transform {
+        pipeline.push(transform);
+    }
+    for transform in &options.value_transforms {
+        pipeline.push(transform.as_ref());
+    }
+
+    let mut table = config.collect()?;
+    tree::visit_leaves_mut(&mut table, &mut |path, value| {
+        for transform in &pipeline {
+            transform.transform(path, value)?;
+        }
+        Ok(())
+    })?;
+    tree::rebuild(table)
+}
+
+#[derive(Debug)]
+struct ExpandEnvRefs {
+    keep_unresolved: bool,
+}
+
+impl ValueTransform for ExpandEnvRefs {
+    fn transform(&self, path: &str, value: &mut Value) -> Result<()> {
+        if let ValueKind::String(s) = &mut value.kind {
+            if s.contains("${") {
+                *s = expand_env_refs(path, s, self.keep_unresolved)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct TrimStrings;
+
+impl ValueTransform for TrimStrings {
+    fn transform(&self, _path: &str, value: &mut Value) -> Result<()> {
+        if let ValueKind::String(s) = &mut value.kind {
+            let trimmed = s.trim();
+            if trimmed.len() != s.len() {
+                *s = trimmed.to_owned();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ExpandHome {
+    home: String,
+}
+
+impl ValueTransform for ExpandHome {
+    fn transform(&self, _path: &str, value: &mut Value) -> Result<()> {
+        if let ValueKind::String(s) = &mut value.kind {
+            if s.as_str() == "~" || s.starts_with("~/") {
+                *s = format!("{}{}", self.home, &s[1..]);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn expand_env_refs(path: &str, s: &str, keep_unresolved: bool) -> Result<String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start..].find('}') else {
+            // Not a reference without the closing brace, so the rest is kept as it is
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start..start + len + 1];
+        let inner = &reference[2..reference.len() - 1];
+        let (var, default) = match inner.split_once(":-") {
+            Some((var, default)) => (var, Some(default)),
+            None => (inner, None),
+        };
+        match (env::var(var), default) {
+            (Ok(value), _) => expanded.push_str(&value),
+            (Err(_), Some(default)) => expanded.push_str(default),
+            (Err(_), None) if keep_unresolved => expanded.push_str(reference),
+            (Err(_), None) => bail!("{var} is not set, but {path} refers to it"),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_env_refs() {
+        env::set_var("SCL_TEST_HOST", "db.internal");
+        env::remove_var("SCL_TEST_UNSET");
+        let expand = |s| expand_env_refs("db.url", s, false).unwrap();
+        assert_eq!(expand("pg://${SCL_TEST_HOST}/app"), "pg://db.internal/app");
+        assert_eq!(expand("${SCL_TEST_UNSET:-localhost}:5432"), "localhost:5432");
+        assert_eq!(expand("${SCL_TEST_HOST:-localhost}"), "db.internal");
+        assert_eq!(expand("cost: $5 ${unclosed"), "cost: $5 ${unclosed");
+    }
+
+    #[test]
+    fn fails_on_unset_env_refs_unless_kept() {
+        env::remove_var("SCL_TEST_UNSET");
+        let error = expand_env_refs("db.url", "${SCL_TEST_UNSET}", false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "SCL_TEST_UNSET is not set, but db.url refers to it"
+        );
+        let kept = expand_env_refs("db.url", "a ${SCL_TEST_UNSET} b", true).unwrap();
+        assert_eq!(kept, "a ${SCL_TEST_UNSET} b");
+    }
+}
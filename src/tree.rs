@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use config::{ConfigError, Map, Source, Value, ValueKind};
+
+// Serves an already merged value tree, so a config can be rebuilt after rewriting its values
+#[derive(Debug, Clone)]
+pub(crate) struct TreeSource(pub(crate) Map<String, Value>);
+
+impl Source for TreeSource {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        Ok(self.0.clone())
+    }
+}
+
+pub(crate) fn rebuild(table: Map<String, Value>) -> Result<config::Config> {
+    Ok(config::Config::builder()
+        .add_source(TreeSource(table))
+        .build()?)
+}
+
+// Calls `f` with the path (eg. `servers[0].host`) of every scalar value in the tree
+pub(crate) fn visit_leaves_mut(
+    table: &mut Map<String, Value>,
+    f: &mut dyn FnMut(&str, &mut Value) -> Result<()>,
+) -> Result<()> {
+    for (key, value) in table.iter_mut() {
+        visit_value_mut(key, value, f)?;
+    }
+    Ok(())
+}
+
+fn visit_value_mut(
+    path: &str,
+    value: &mut Value,
+    f: &mut dyn FnMut(&str, &mut Value) -> Result<()>,
+) -> Result<()> {
+    match &mut value.kind {
+        ValueKind::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                visit_value_mut(&format!("{path}.{key}"), value, f)?;
+            }
+        }
+        ValueKind::Array(array) => {
+            for (i, value) in array.iter_mut().enumerate() {
+                visit_value_mut(&format!("{path}[{i}]"), value, f)?;
+            }
+        }
+        _ => return f(path, value),
+    }
+    Ok(())
+}
+
+// Removes every scalar value for which `keep` returns false, from tables and arrays alike. Paths
+// are given as for visit_leaves_mut()
+pub(crate) fn retain_leaves(table: &mut Map<String, Value>, keep: &dyn Fn(&str, &Value) -> bool) {
+    table.retain(|key, value| retain_value(key, value, keep));
+}
+
+fn retain_value(path: &str, value: &mut Value, keep: &dyn Fn(&str, &Value) -> bool) -> bool {
+    match &mut value.kind {
+        ValueKind::Table(table) => {
+            table.retain(|key, value| retain_value(&format!("{path}.{key}"), value, keep))
+        }
+        ValueKind::Array(array) => {
+            let mut i = 0;
+            array.retain_mut(|value| {
+                i += 1;
+                retain_value(&format!("{path}[{}]", i - 1), value, keep)
+            });
+        }
+        _ => return keep(path, value),
+    }
+    true
+}
+
+// Lookups by dotted key, eg. `server.tls.cert`, treating anything that isn't a table as a leaf
+pub(crate) fn get_path<'a>(table: &'a Map<String, Value>, key: &str) -> Option<&'a Value> {
+    match key.split_once('.') {
+        None => table.get(key),
+        Some((first, rest)) => match &table.get(first)?.kind {
+            ValueKind::Table(table) => get_path(table, rest),
+            _ => None,
+        },
+    }
+}
+
+pub(crate) fn remove_path(table: &mut Map<String, Value>, key: &str) -> Option<Value> {
+    match key.split_once('.') {
+        None => table.remove(key),
+        Some((first, rest)) => match &mut table.get_mut(first)?.kind {
+            ValueKind::Table(table) => remove_path(table, rest),
+            _ => None,
+        },
+    }
+}
+
+// Sets the value at `key`, replacing any non-table values in the way with tables
+pub(crate) fn set_path(table: &mut Map<String, Value>, key: &str, value: Value) {
+    let Some((first, rest)) = key.split_once('.') else {
+        table.insert(key.to_owned(), value);
+        return;
+    };
+    let child = table
+        .entry(first.to_owned())
+        .or_insert_with(|| Value::new(None, ValueKind::Table(Map::new())));
+    if !matches!(child.kind, ValueKind::Table(_)) {
+        *child = Value::new(None, ValueKind::Table(Map::new()));
+    }
+    if let ValueKind::Table(child) = &mut child.kind {
+        set_path(child, rest, value);
+    }
+}
+
+// Merges `overlay` into `table`: tables are merged key by key, and any other value in `overlay`
+// (arrays included) replaces the one in `table`
+pub(crate) fn merge(table: &mut Map<String, Value>, overlay: Map<String, Value>) {
+    for (key, value) in overlay {
+        if let Some(existing) = table.get_mut(&key) {
+            if let (ValueKind::Table(existing), ValueKind::Table(overlay)) =
+                (&mut existing.kind, &value.kind)
+            {
+                merge(existing, overlay.clone());
+                continue;
+            }
+        }
+        table.insert(key, value);
+    }
+}
+
+// Every leaf by dotted key, with arrays treated as leaves. Keys that are already dotted (as
+// collected from env vars) are kept as they are
+pub(crate) fn flatten(table: &Map<String, Value>) -> BTreeMap<String, Value> {
+    let mut values = BTreeMap::new();
+    flatten_into(None, table, &mut values);
+    values
+}
+
+fn flatten_into(
+    prefix: Option<&str>,
+    table: &Map<String, Value>,
+    values: &mut BTreeMap<String, Value>,
+) {
+    for (key, value) in table {
+        let key = match prefix {
+            Some(prefix) => format!("{prefix}.{key}"),
+            None => key.clone(),
+        };
+        match &value.kind {
+            ValueKind::Table(table) => flatten_into(Some(&key), table, values),
+            _ => {
+                values.insert(key, value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(entries: &[(&str, Value)]) -> Map<String, Value> {
+        let mut table = Map::new();
+        for (key, value) in entries {
+            set_path(&mut table, key, value.clone());
+        }
+        table
+    }
+
+    #[test]
+    fn merges_tables_key_by_key() {
+        let array = |values: &[i64]| {
+            let values = values.iter().map(|value| Value::new(None, *value)).collect();
+            Value::new(None, ValueKind::Array(values))
+        };
+        let mut base = table(&[
+            ("db.host", Value::new(None, "localhost")),
+            ("db.port", Value::new(None, 5432)),
+            ("ids", array(&[1, 2])),
+        ]);
+        let overlay = table(&[
+            ("db.port", Value::new(None, 6432)),
+            ("ids", array(&[3])),
+        ]);
+        merge(&mut base, overlay);
+        let values = flatten(&base);
+        assert_eq!(values["db.host"].kind, ValueKind::String("localhost".into()));
+        assert_eq!(values["db.port"].kind, ValueKind::I64(6432));
+        assert_eq!(values["ids"].kind, array(&[3]).kind);
+    }
+
+    #[test]
+    fn retains_leaves_by_path() {
+        let mut values = table(&[
+            ("db.host", Value::new(None, "localhost")),
+            ("db.password", Value::new(None, "hunter2")),
+        ]);
+        let tokens = ["a", "b"].map(|token| Value::new(None, token)).to_vec();
+        set_path(&mut values, "tokens", Value::new(None, ValueKind::Array(tokens)));
+        retain_leaves(&mut values, &|path, _| path != "db.password" && path != "tokens[1]");
+        let values = flatten(&values);
+        assert_eq!(values.keys().collect::<Vec<_>>(), ["db.host", "tokens"]);
+        let ValueKind::Array(tokens) = &values["tokens"].kind else {
+            panic!("tokens is not an array");
+        };
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].kind, ValueKind::String("a".into()));
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+
+use config::{ConfigError, Unexpected};
+
+use crate::render;
+
+// A config error in a structured form, eg. for CI to annotate the offending file rather than
+// print the error message. Built from the errors returned by validate(),
+// load_all_environments() and init
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    // The dotted key the error is about, if known, eg. `server.port`
+    pub key: Option<String>,
+    // One of `type_mismatch`, `not_found`, `parse` or `other`
+    pub kind: &'static str,
+    // The type that was expected, for type mismatches, eg. `u16`
+    pub expected: Option<String>,
+    // The kind of value that was found instead, for type mismatches, eg. `string`
+    pub found: Option<String>,
+    // The file (or other source) the value came from, if known
+    pub origin: Option<String>,
+    pub message: String,
+}
+
+impl ValidationError {
+    // Renders the error as a single JSON object, leaving out unknown fields
+    pub fn to_json(&self) -> String {
+        let fields = [
+            ("key", self.key.as_deref()),
+            ("kind", Some(self.kind)),
+            ("expected", self.expected.as_deref()),
+            ("found", self.found.as_deref()),
+            ("origin", self.origin.as_deref()),
+            ("message", Some(self.message.as_str())),
+        ];
+        let mut out = String::from("{");
+        for (name, value) in fields {
+            let Some(value) = value else {
+                continue;
+            };
+            if out.len() > 1 {
+                out.push_str(", ");
+            }
+            render::json_string(name, &mut out);
+            out.push_str(": ");
+            render::json_string(value, &mut out);
+        }
+        out.push('}');
+        out
+    }
+}
+
+// The context deserialize_at() gives an error, holding the path of the value that failed (`.` for
+// the root), eg. `servers[0].port`
+#[derive(Debug)]
+pub(crate) struct InvalidValueAt(pub(crate) String);
+
+impl fmt::Display for InvalidValueAt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid config value at {}", self.0)
+    }
+}
+
+impl From<&anyhow::Error> for ValidationError {
+    fn from(error: &anyhow::Error) -> Self {
+        // The path of the field being deserialized is more precise than the key config gives
+        let path = error
+            .downcast_ref::<InvalidValueAt>()
+            .map(|at| at.0.clone())
+            .filter(|path| path != ".");
+        let mut validation_error = Self {
+            key: path,
+            kind: "other",
+            expected: None,
+            found: None,
+            origin: None,
+            message: format!("{error:#}"),
+        };
+        let Some(config_error) = error.chain().find_map(|e| e.downcast_ref::<ConfigError>()) else {
+            return validation_error;
+        };
+        match config_error {
+            ConfigError::Type {
+                origin,
+                unexpected,
+                expected,
+                key,
+            } => {
+                validation_error.kind = "type_mismatch";
+                validation_error.key = validation_error.key.or_else(|| key.clone());
+                validation_error.expected = Some((*expected).to_owned());
+                validation_error.found = Some(found(unexpected).to_owned());
+                validation_error.origin = origin.clone();
+            }
+            ConfigError::NotFound(key) => {
+                validation_error.kind = "not_found";
+                validation_error.key = validation_error.key.or_else(|| Some(key.clone()));
+            }
+            ConfigError::FileParse { uri, .. } => {
+                validation_error.kind = "parse";
+                validation_error.origin = uri.clone();
+            }
+            _ => {}
+        }
+        validation_error
+    }
+}
+
+fn found(unexpected: &Unexpected) -> &'static str {
+    match unexpected {
+        Unexpected::Bool(_) => "boolean",
+        Unexpected::I64(_) | Unexpected::I128(_) | Unexpected::U64(_) | Unexpected::U128(_) => {
+            "integer"
+        }
+        Unexpected::Float(_) => "float",
+        Unexpected::Str(_) => "string",
+        Unexpected::Unit => "null",
+        Unexpected::Seq => "array",
+        Unexpected::Map => "table",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn takes_the_key_from_the_path_of_the_field() {
+        type Sections = HashMap<String, HashMap<String, u16>>;
+        let config = config::Config::builder()
+            .set_default("server.workers", 4)
+            .unwrap()
+            .set_default("server.port", "http")
+            .unwrap()
+            .build()
+            .unwrap();
+        let error = crate::deserialize_at::<Sections, _>(config, None).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid config value at server.port");
+        let validation_error = ValidationError::from(&error);
+        assert_eq!(validation_error.key.as_deref(), Some("server.port"));
+    }
+}
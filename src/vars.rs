@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::sync::Mutex;
+
+// Vars exported from .env files by earlier loads, along with the value exported. They aren't
+// taken from the process env by later loads (unless something else changed them since), so a
+// reload after switching ENV reads the new environment's .env files rather than keeping the vars
+// of the old one
+static EXPORTED: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+// The process env var `name`, unless an earlier load exported it from a .env file (and nothing
+// has changed it since)
+pub(crate) fn process_var(name: &str) -> Option<String> {
+    let value = env::var(name).ok()?;
+    let exported = EXPORTED.lock().unwrap().get(name) == Some(&value);
+    (!exported).then_some(value)
+}
+
+// The env vars a load reads, starting from a snapshot of the process env. Like dotenvy, vars
+// from .env files never override vars that are already set. They are exported to the process
+// env as well, unless the load is isolated
+pub(crate) struct Vars {
+    vars: HashMap<String, String>,
+    export: bool,
+    // The vars set from encrypted .env files
+    secrets: BTreeSet<String>,
+}
+
+impl Vars {
+    pub(crate) fn from_process_env(export: bool) -> Self {
+        let exported = EXPORTED.lock().unwrap();
+        Self {
+            vars: env::vars()
+                .filter(|(key, value)| exported.get(key) != Some(value))
+                .collect(),
+            export,
+            secrets: BTreeSet::new(),
+        }
+    }
+
+    // No vars at all, for loads that mustn't depend on the env they run in
+    pub(crate) fn empty() -> Self {
+        Self {
+            vars: HashMap::new(),
+            export: false,
+            secrets: BTreeSet::new(),
+        }
+    }
+
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(&str) -> bool) {
+        self.vars.retain(|key, _| f(key));
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(String::as_str)
+    }
+
+    pub(crate) fn load(
+        &mut self,
+        iter: impl IntoIterator<Item = dotenvy::Result<(String, String)>>,
+    ) -> dotenvy::Result<()> {
+        for item in iter {
+            let (key, value) = item?;
+            self.insert(key, value);
+        }
+        Ok(())
+    }
+
+    // Like load(), for the vars of an encrypted .env file
+    pub(crate) fn load_secrets(
+        &mut self,
+        iter: impl IntoIterator<Item = dotenvy::Result<(String, String)>>,
+    ) -> dotenvy::Result<()> {
+        for item in iter {
+            let (key, value) = item?;
+            if self.insert(key.clone(), value) {
+                self.secrets.insert(key);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn secrets(&self) -> &BTreeSet<String> {
+        &self.secrets
+    }
+
+    // Whether the var was set, ie. wasn't set already
+    fn insert(&mut self, key: String, value: String) -> bool {
+        if self.vars.contains_key(&key) {
+            return false;
+        }
+        if self.export {
+            env::set_var(&key, &value);
+            EXPORTED
+                .lock()
+                .unwrap()
+                .insert(key.clone(), value.clone());
+        }
+        self.vars.insert(key, value);
+        true
+    }
+
+    // All names lowercased, so that the prefix, separators and keys of env vars all match
+    // whatever their case. When a var is set in several cases, the first in sort order (ie. the
+    // most uppercase one) wins, so the result doesn't depend on the env's hash order
+    pub(crate) fn to_lowercase_map(&self) -> config::Map<String, String> {
+        let mut sorted: Vec<(&String, &String)> = self.vars.iter().collect();
+        sorted.sort();
+        let mut map = config::Map::new();
+        for (key, value) in sorted {
+            map.entry(key.to_lowercase())
+                .or_insert_with(|| value.clone());
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exported_vars_are_not_process_env_on_the_next_load() {
+        let mut vars = Vars::from_process_env(true);
+        vars.load([Ok(("SCL_TEST_EXPORTED".into(), "dev".into()))]).unwrap();
+        assert_eq!(env::var("SCL_TEST_EXPORTED").unwrap(), "dev");
+
+        let mut vars = Vars::from_process_env(true);
+        assert_eq!(vars.get("SCL_TEST_EXPORTED"), None);
+        vars.load([Ok(("SCL_TEST_EXPORTED".into(), "prod".into()))]).unwrap();
+        assert_eq!(vars.get("SCL_TEST_EXPORTED"), Some("prod"));
+        assert_eq!(env::var("SCL_TEST_EXPORTED").unwrap(), "prod");
+    }
+
+    #[test]
+    fn vars_changed_since_export_are_process_env() {
+        let mut vars = Vars::from_process_env(true);
+        vars.load([Ok(("SCL_TEST_CHANGED".into(), "dev".into()))]).unwrap();
+        env::set_var("SCL_TEST_CHANGED", "set by hand");
+
+        let mut vars = Vars::from_process_env(true);
+        vars.load([Ok(("SCL_TEST_CHANGED".into(), "prod".into()))]).unwrap();
+        assert_eq!(vars.get("SCL_TEST_CHANGED"), Some("set by hand"));
+    }
+}
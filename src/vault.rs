@@ -0,0 +1,102 @@
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+
+use anyhow::{anyhow, bail, Context, Result};
+use config::{Source, Value, ValueKind};
+
+use crate::tree;
+
+const VAULT_PREFIX: &str = "vault:";
+
+// Replaces every string value of the form `vault:<path>#<field>`, eg.
+// `vault:secret/data/app#db_password`, with that field of the secret read from Vault.
+// VAULT_ADDR and VAULT_TOKEN are only required once such a value is found. The paths of the
+// values replaced are added to `secret_paths`
+pub(crate) fn resolve(
+    config: config::Config,
+    secret_paths: &mut BTreeSet<String>,
+) -> Result<config::Config> {
+    let mut table = config.collect()?;
+    let mut client: Option<Vault> = None;
+    tree::visit_leaves_mut(&mut table, &mut |key, value| {
+        let reference = match value.kind {
+            ValueKind::String(ref s) => match s.strip_prefix(VAULT_PREFIX) {
+                Some(reference) => reference.to_owned(),
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        let (path, field) = reference.split_once('#').ok_or_else(|| {
+            anyhow!("Vault reference for {key} must be of the form vault:<path>#<field>")
+        })?;
+        if client.is_none() {
+            client = Some(Vault::from_env()?);
+        }
+        let secret = client.as_mut().unwrap().read(path, field)?;
+        let origin = value.origin().map(str::to_owned);
+        *value = Value::new(origin.as_ref(), ValueKind::String(secret));
+        secret_paths.insert(key.to_owned());
+        Ok(())
+    })?;
+
+    if client.is_none() {
+        return Ok(config);
+    }
+    tree::rebuild(table)
+}
+
+// Drops every `vault:` reference, as if the values weren't there, for SecretsMode::Disabled
+pub(crate) fn skip(config: config::Config) -> Result<config::Config> {
+    let mut table = config.collect()?;
+    tree::retain_leaves(&mut table, &|_, value| !is_reference(value));
+    tree::rebuild(table)
+}
+
+// Whether the value is a `vault:` reference that resolve() will replace
+pub(crate) fn is_reference(value: &Value) -> bool {
+    matches!(&value.kind, ValueKind::String(s) if s.starts_with(VAULT_PREFIX))
+}
+
+struct Vault {
+    addr: String,
+    token: String,
+    // Several keys usually reference fields of the same secret, so each path is only fetched once
+    secrets: HashMap<String, serde_json::Value>,
+}
+
+impl Vault {
+    fn from_env() -> Result<Self> {
+        let addr =
+            env::var("VAULT_ADDR").context("VAULT_ADDR must be set to resolve vault: values")?;
+        let token =
+            env::var("VAULT_TOKEN").context("VAULT_TOKEN must be set to resolve vault: values")?;
+        Ok(Self {
+            addr: addr.trim_end_matches('/').into(),
+            token,
+            secrets: HashMap::new(),
+        })
+    }
+
+    fn read(&mut self, path: &str, field: &str) -> Result<String> {
+        if !self.secrets.contains_key(path) {
+            let url = format!("{}/v1/{}", self.addr, path.trim_start_matches('/'));
+            let secret: serde_json::Value = ureq::get(&url)
+                .header("X-Vault-Token", &self.token)
+                .call()
+                .with_context(|| format!("Failed to read {path} from Vault"))?
+                .into_body()
+                .read_json()
+                .with_context(|| format!("Invalid response from Vault for {path}"))?;
+            self.secrets.insert(path.into(), secret);
+        }
+
+        let secret = &self.secrets[path];
+        // KV v2 nests the secret's fields under `data.data`, KV v1 directly under `data`
+        let data = secret.pointer("/data/data").or_else(|| secret.get("data"));
+        match data.and_then(|data| data.get(field)) {
+            Some(serde_json::Value::String(value)) => Ok(value.clone()),
+            Some(value) => Ok(value.to_string()),
+            None => bail!("Vault secret {path} has no field {field}"),
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use config::{FileFormat, Source};
+use yaml_rust2::parser::{Event, EventReceiver, Parser};
+
+use crate::file_refs;
+use crate::files::{self, FileProvider};
+use crate::layer::{Layer, LayerKind};
+use crate::secrets::{self, WithoutSkipped};
+use crate::tree::{self, TreeSource};
+
+// Prefix of the keys under which earlier documents are nested to make their anchors available
+const ANCHORS_KEY: &str = "__simple_config_loader_anchors";
+
+// Guardrails for yaml files that might come from outside our direct control. They are checked
+// on the parser's event stream, before the file is turned into config values, so a pathological
+// file fails to load instead of using up memory
+#[derive(Debug, Clone, Default)]
+pub struct YamlLimits {
+    // Maximum size of a file in bytes
+    pub max_size: Option<usize>,
+    // Maximum nesting depth of mappings and sequences
+    pub max_depth: Option<usize>,
+    // Maximum number of nodes a file expands to once every alias is replaced by the node it
+    // refers to, which catches "billion laughs" style files
+    pub max_alias_expansion: Option<u64>,
+}
+
+pub(crate) struct Document {
+    pub(crate) path: String,
+    pub(crate) kind: LayerKind,
+    pub(crate) text: String,
+}
+
+impl Document {
+    // `None` if the file doesn't exist
+    pub(crate) fn read(
+        files: &dyn FileProvider,
+        path: String,
+        kind: LayerKind,
+    ) -> Result<Option<Self>> {
+        match files.read(Path::new(&path)) {
+            Ok(contents) => {
+                let text = files::text(&path, contents)?;
+                Ok(Some(Self { path, kind, text }))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {path}")),
+        }
+    }
+}
+
+// Documents are given from lowest to highest precedence. With `cross_file_anchors`, each
+// document is parsed with all earlier documents nested under hidden keys ahead of it, so it can
+// reference their anchors. The hidden keys are dropped again after parsing, which leaves each
+// layer with only its own keys and keeps the usual override semantics
+// With `cross_file_anchors` the depth and alias limits apply to the combined text that is parsed
+pub(crate) fn layers(
+    mut documents: Vec<Document>,
+    cross_file_anchors: bool,
+    limits: &YamlLimits,
+) -> Result<Vec<Layer>> {
+    for document in &documents {
+        if let Some(max_size) = limits.max_size {
+            if document.text.len() > max_size {
+                bail!(
+                    "{} is {} bytes, more than the limit of {max_size}",
+                    document.path,
+                    document.text.len()
+                );
+            }
+        }
+    }
+
+    for document in &mut documents {
+        document.text = file_refs::mark(&document.text);
+    }
+
+    if !cross_file_anchors {
+        for document in &documents {
+            check_structure(&document.path, &document.text, limits)?;
+        }
+        return Ok(documents
+            .into_iter()
+            .map(|document| {
+                let source = config::File::from_str(&document.text, FileFormat::Yaml);
+                Layer::new(document.path, document.kind, WithoutSkipped(Box::new(source)))
+            })
+            .collect());
+    }
+
+    let mut layers = vec![];
+    for (i, document) in documents.iter().enumerate() {
+        let text = with_anchors_from(&documents[..i], &document.text);
+        check_structure(&document.path, &text, limits)?;
+        let mut table = config::File::from_str(&text, FileFormat::Yaml)
+            .collect()
+            .with_context(|| format!("Failed to parse {}", document.path))?;
+        table.retain(|key, _| !key.starts_with(ANCHORS_KEY));
+        tree::retain_leaves(&mut table, &|_, value| !secrets::is_skipped(value));
+        layers.push(Layer::new(
+            document.path.clone(),
+            document.kind,
+            TreeSource(table),
+        ));
+    }
+    Ok(layers)
+}
+
+fn with_anchors_from(previous: &[Document], text: &str) -> String {
+    let mut combined = String::new();
+    for (i, document) in previous.iter().enumerate() {
+        combined.push_str(&format!("{ANCHORS_KEY}{i}:\n"));
+        // Document markers can't be nested, and every file is a single document anyway
+        for line in document
+            .text
+            .lines()
+            .filter(|line| !matches!(*line, "---" | "..."))
+        {
+            combined.push_str("  ");
+            combined.push_str(line);
+            combined.push('\n');
+        }
+    }
+    combined.push_str(text);
+    combined
+}
+
+// Follows a yaml file line by line for the rewrites of tagged values, which must leave the lines
+// of block scalars (`key: |` or `key: >`) alone since those are text rather than yaml
+#[derive(Default)]
+pub(crate) struct BlockScalars {
+    // The indentation of the line that started the block scalar we're in
+    indent: Option<usize>,
+}
+
+impl BlockScalars {
+    // Whether `line`, the next line of the file, is part of a block scalar
+    pub(crate) fn contains(&mut self, line: &str) -> bool {
+        let indent = line.len() - line.trim_start().len();
+        if let Some(block_indent) = self.indent {
+            if line.trim().is_empty() || indent > block_indent {
+                return true;
+            }
+            self.indent = None;
+        }
+        self.indent = block_scalar_indent(line);
+        false
+    }
+}
+
+// The indentation of the node whose value on `line` is a block scalar header (eg. `|`, `>-` or
+// `|2+`), which is the column of the key for `key: |` and of the dash for `- |`
+fn block_scalar_indent(line: &str) -> Option<usize> {
+    let line = match line.find(" #") {
+        Some(comment) => &line[..comment],
+        None => line,
+    };
+    let (before, header) = line.trim_end().rsplit_once(char::is_whitespace)?;
+    let is_header = header.starts_with(['|', '>'])
+        && header[1..]
+            .chars()
+            .all(|c| matches!(c, '+' | '-' | '1'..='9'));
+    if !is_header {
+        return None;
+    }
+    let before = before.trim_end();
+    if before.ends_with('-') {
+        return Some(before.len() - 1);
+    }
+    if !before.ends_with(':') {
+        return None;
+    }
+    // Past any dashes of list items the key is in, eg. `- key: |`
+    let key = before.trim_start_matches(|c: char| c == '-' || c.is_whitespace());
+    Some(before.len() - key.len())
+}
+
+fn check_structure(path: &str, text: &str, limits: &YamlLimits) -> Result<()> {
+    if limits.max_depth.is_none() && limits.max_alias_expansion.is_none() {
+        return Ok(());
+    }
+    let mut measure = Measure::default();
+    Parser::new_from_str(text)
+        .load(&mut measure, true)
+        .with_context(|| format!("Failed to parse {path}"))?;
+
+    if let Some(max_depth) = limits.max_depth {
+        if measure.max_depth > max_depth {
+            bail!(
+                "{path} is nested {} levels deep, more than the limit of {max_depth}",
+                measure.max_depth
+            );
+        }
+    }
+    if let Some(max_alias_expansion) = limits.max_alias_expansion {
+        if measure.nodes > max_alias_expansion {
+            bail!(
+                "{path} expands to {} nodes through aliases, more than the limit of \
+                 {max_alias_expansion}",
+                measure.nodes
+            );
+        }
+    }
+    Ok(())
+}
+
+// Counts nodes without building them, so aliases are never actually expanded
+#[derive(Default)]
+struct Measure {
+    // The anchor and node count of every open mapping or sequence
+    open: Vec<(usize, u64)>,
+    // The expanded node count of every anchored node
+    anchored: HashMap<usize, u64>,
+    nodes: u64,
+    max_depth: usize,
+}
+
+impl Measure {
+    fn add(&mut self, anchor: usize, count: u64) {
+        if anchor > 0 {
+            self.anchored.insert(anchor, count);
+        }
+        match self.open.last_mut() {
+            Some((_, parent)) => *parent = parent.saturating_add(count),
+            None => self.nodes = self.nodes.saturating_add(count),
+        }
+    }
+}
+
+impl EventReceiver for Measure {
+    fn on_event(&mut self, event: Event) {
+        match event {
+            Event::Scalar(_, _, anchor, ..) => self.add(anchor, 1),
+            Event::Alias(anchor) => {
+                let count = self.anchored.get(&anchor).copied().unwrap_or(1);
+                self.add(0, count);
+            }
+            Event::SequenceStart(anchor, ..) | Event::MappingStart(anchor, ..) => {
+                self.open.push((anchor, 1));
+                self.max_depth = self.max_depth.max(self.open.len());
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some((anchor, count)) = self.open.pop() {
+                    self.add(anchor, count);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_nodes_as_if_aliases_were_expanded() {
+        // 1 for the root mapping, then the keys (1 each) and their sequences: `a` is 1 + 2, `b`
+        // is 1 + 2 * 3 and `c` is 1 + 2 * 7
+        let text = "a: &a [x, x]\nb: &b [*a, *a]\nc: [*b, *b]\n";
+        let limits = |max_alias_expansion| YamlLimits {
+            max_alias_expansion: Some(max_alias_expansion),
+            ..Default::default()
+        };
+        assert!(check_structure("default.yaml", text, &limits(29)).is_ok());
+        assert_eq!(
+            check_structure("default.yaml", text, &limits(28))
+                .unwrap_err()
+                .to_string(),
+            "default.yaml expands to 29 nodes through aliases, more than the limit of 28"
+        );
+    }
+
+    #[test]
+    fn measures_nesting_depth() {
+        // The root mapping is the first level
+        let limits = YamlLimits {
+            max_depth: Some(3),
+            ..Default::default()
+        };
+        assert!(check_structure("default.yaml", "a:\n  b: [1]\n", &limits).is_ok());
+        assert!(check_structure("default.yaml", "a:\n  b: [[1]]\n", &limits).is_err());
+    }
+
+    fn document(path: &str, text: &str) -> Document {
+        Document {
+            path: path.into(),
+            kind: LayerKind::File,
+            text: text.into(),
+        }
+    }
+
+    #[test]
+    fn nests_earlier_documents_under_hidden_keys() {
+        let previous = [document("default.yaml", "---\ntimeout: &timeout 5\n")];
+        assert_eq!(
+            with_anchors_from(&previous, "server:\n  timeout: *timeout\n"),
+            format!("{ANCHORS_KEY}0:\n  timeout: &timeout 5\nserver:\n  timeout: *timeout\n")
+        );
+    }
+
+    #[test]
+    fn resolves_anchors_across_files() {
+        let documents = vec![
+            document("default.yaml", "timeout: &timeout 5\n"),
+            document("prod.yaml", "server:\n  timeout: *timeout\n"),
+        ];
+        let layers = layers(documents, true, &YamlLimits::default()).unwrap();
+        let values = layers[1].values().unwrap();
+        assert_eq!(values.keys().collect::<Vec<_>>(), ["server.timeout"]);
+        assert_eq!(values["server.timeout"].kind, config::ValueKind::I64(5));
+    }
+}